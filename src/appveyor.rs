@@ -1,5 +1,13 @@
 #![allow(bad_style)]
 
+use futures::Future;
+
+use errors::*;
+use http::{self, Session};
+use provider::{self, CiProvider, Status};
+use MyFuture;
+use Repo;
+
 #[derive(RustcDecodable, Debug)]
 pub struct History {
     pub project: Project,
@@ -36,6 +44,7 @@ pub struct Build {
 #[derive(RustcDecodable, Debug)]
 pub struct Job {
     pub jobId: String,
+    pub allowFailure: bool,
     pub status: String,
 }
 
@@ -43,3 +52,134 @@ pub struct Job {
 pub struct LastBuild {
     pub build: Build,
 }
+
+pub struct AppVeyor {
+    pub session: Session,
+    /// Token for repositories without one of their own, if there is one.
+    pub token: Option<String>,
+    /// Root of the AppVeyor instance, e.g. `https://ci.appveyor.com`.
+    pub base_url: String,
+    /// Account for repositories which don't configure their own.
+    pub account_name: Option<String>,
+}
+
+impl AppVeyor {
+    /// Returns the instance `repo` builds on, which may differ from the
+    /// default.
+    fn base_url(&self, repo: &Repo) -> String {
+        repo.appveyor
+            .as_ref()
+            .and_then(|c| c.url.clone())
+            .unwrap_or_else(|| self.base_url.clone())
+    }
+
+    /// Returns the token for `repo`'s instance, its own if it has one. It's
+    /// never empty for the repositories this covers.
+    fn token(&self, repo: &Repo) -> String {
+        repo.appveyor
+            .as_ref()
+            .and_then(|c| c.token.clone())
+            .or_else(|| self.token.clone())
+            .unwrap_or_default()
+    }
+
+    /// Returns the account and project slug `repo` builds under.
+    fn project(&self, repo: &Repo) -> BorsResult<(String, String)> {
+        let configured = repo.appveyor.as_ref();
+        let account = configured
+            .and_then(|c| c.account.as_ref())
+            .or(self.account_name.as_ref());
+        let account = match account {
+            Some(account) => account.clone(),
+            None => return Err(BorsErrorKind::NoAccount("appveyor", repo.full_name()).into()),
+        };
+        let project = configured
+            .and_then(|c| c.project.clone())
+            .unwrap_or_else(|| repo.name.clone());
+        Ok((account, project))
+    }
+}
+
+impl CiProvider for AppVeyor {
+    fn name(&self) -> &'static str {
+        "appveyor"
+    }
+
+    fn covers(&self, repo: &Repo) -> bool {
+        self.token.is_some() || repo.appveyor.as_ref().map_or(false, |c| c.token.is_some())
+    }
+
+    fn budget(&self, repo: &Repo) -> Option<http::Budget> {
+        self.session.budget(self.name(), &self.token(repo))
+    }
+
+    fn builds(&self, repo: &Repo, branch: Option<&str>) -> MyFuture<Vec<provider::Build>> {
+        let (account, project) = match self.project(repo) {
+            Ok(project) => project,
+            Err(e) => return Box::new(futures::failed(e)),
+        };
+        let mut url = format!("/projects/{}/{}/history?recordsNumber=10", account, project);
+        if let Some(branch) = branch {
+            url.push_str(&format!("&branch={}", branch));
+        }
+        let history =
+            http::appveyor_get(&self.session, &self.base_url(repo), &url, &self.token(repo));
+        let builds = history.map(|history: History| {
+            history
+                .builds
+                .iter()
+                .map(|build| provider::Build {
+                    id: build.version.clone(),
+                    number: build.buildNumber as u64,
+                    branch: build.branch.clone(),
+                    commit: Some(build.commitId.clone()),
+                    workflow: None,
+                    status: classify(&build.status),
+                })
+                .collect()
+        });
+        Box::new(builds)
+    }
+
+    fn jobs(&self, repo: &Repo, build: &provider::Build) -> MyFuture<Vec<provider::Job>> {
+        let (account, project) = match self.project(repo) {
+            Ok(project) => project,
+            Err(e) => return Box::new(futures::failed(e)),
+        };
+        let url = format!("/projects/{}/{}/build/{}", account, project, build.id);
+        let build =
+            http::appveyor_get(&self.session, &self.base_url(repo), &url, &self.token(repo));
+        let jobs = build.map(|last: LastBuild| {
+            // leave out `allow_failures` matrix entries
+            last.build
+                .jobs
+                .iter()
+                .filter(|job| !job.allowFailure)
+                .map(|job| provider::Job {
+                    id: job.jobId.clone(),
+                    status: classify(&job.status),
+                })
+                .collect()
+        });
+        Box::new(jobs)
+    }
+
+    fn cancel(&self, repo: &Repo, build: &provider::Build) -> MyFuture<()> {
+        let (account, project) = match self.project(repo) {
+            Ok(project) => project,
+            Err(e) => return Box::new(futures::failed(e)),
+        };
+        let url = format!("/builds/{}/{}/{}", account, project, build.id);
+        http::appveyor_delete(&self.session, &self.base_url(repo), &url, &self.token(repo))
+    }
+}
+
+fn classify(status: &str) -> Status {
+    match status {
+        "queued" => Status::Queued,
+        "success" => Status::Passed,
+        "failed" => Status::Failed,
+        "cancelled" | "cancelling" => Status::Canceled,
+        _ => Status::Running,
+    }
+}
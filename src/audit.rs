@@ -0,0 +1,163 @@
+//! An append-only record of every build cancelled, or that would have been in
+//! a dry run, kept as one JSON object per line in the `--audit-log` file so
+//! "why was my build killed?" can be answered with `cancelbot audit`.
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use rustc_serialize::json;
+use time;
+
+use errors::*;
+
+#[derive(RustcEncodable, RustcDecodable, Clone, Debug, PartialEq)]
+pub struct Entry {
+    /// When the build was cancelled, as an RFC 3339 UTC timestamp.
+    pub timestamp: String,
+    pub provider: String,
+    /// `user/name`
+    pub repo: String,
+    pub branch: String,
+    pub build: String,
+    pub number: u64,
+    pub commit: Option<String>,
+    /// Kind of reason, as in `policy::Reason::kind`.
+    pub reason: String,
+    /// The reason spelled out, e.g. "it's superseded by 12".
+    pub detail: String,
+    pub dry_run: bool,
+}
+
+impl Entry {
+    /// One line summing up the entry, for `cancelbot audit`.
+    pub fn describe(&self) -> String {
+        format!(
+            "{} {} {} build {} ({}) of {} on {}{} as {}",
+            self.timestamp,
+            if self.dry_run {
+                "would have cancelled"
+            } else {
+                "cancelled"
+            },
+            self.provider,
+            self.number,
+            self.build,
+            self.repo,
+            self.branch,
+            match self.commit {
+                Some(ref commit) => format!(" at {}", commit),
+                None => String::new(),
+            },
+            self.detail
+        )
+    }
+}
+
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+impl AuditLog {
+    pub fn new(path: &Path) -> AuditLog {
+        AuditLog {
+            path: path.to_path_buf(),
+        }
+    }
+
+    /// Appends `entry`, creating the log if need be.
+    pub fn record(&self, entry: &Entry) -> BorsResult<()> {
+        let line = try!(
+            json::encode(entry).map_err(|e| format!("failed to encode audit log entry: {}", e))
+        );
+        let mut file = try!(OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .chain_err(|| format!("failed to open audit log {}", self.path.display())));
+        // a single write of the whole line keeps concurrent appends intact
+        file.write_all(format!("{}\n", line).as_bytes())
+            .chain_err(|| format!("failed to write audit log {}", self.path.display()))
+    }
+
+    /// Every entry recorded so far, oldest first.
+    pub fn entries(&self) -> BorsResult<Vec<Entry>> {
+        let file = try!(File::open(&self.path)
+            .chain_err(|| format!("failed to open audit log {}", self.path.display())));
+        let mut entries = Vec::new();
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line = try!(line.chain_err(|| format!("failed to read {}", self.path.display())));
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry = try!(json::decode(&line).chain_err(|| {
+                format!("invalid entry on line {} of {}", i + 1, self.path.display())
+            }));
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+}
+
+/// What `cancelbot audit` is looking for.
+#[derive(Default)]
+pub struct Query {
+    pub repo: Option<String>,
+    /// Build id or number.
+    pub build: Option<String>,
+    /// RFC 3339 UTC timestamps, as returned by `parse_time`.
+    pub since: Option<String>,
+    pub until: Option<String>,
+}
+
+impl Query {
+    pub fn matches(&self, entry: &Entry) -> bool {
+        if let Some(ref repo) = self.repo {
+            if *repo != entry.repo {
+                return false;
+            }
+        }
+        if let Some(ref build) = self.build {
+            if *build != entry.build && *build != entry.number.to_string() {
+                return false;
+            }
+        }
+        // timestamps are all in the same format and timezone, so they sort
+        // chronologically as strings
+        if let Some(ref since) = self.since {
+            if entry.timestamp < *since {
+                return false;
+            }
+        }
+        if let Some(ref until) = self.until {
+            if entry.timestamp >= *until {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+pub fn now() -> String {
+    time::now_utc().rfc3339().to_string()
+}
+
+/// Parses a point in time given as `2019-05-01`, `2019-05-01T12:00:00Z` or
+/// as a duration before now, parsed by `ago`.
+pub fn parse_time<F>(s: &str, ago: F) -> Option<String>
+where
+    F: Fn(&str) -> Option<Duration>,
+{
+    for format in &["%Y-%m-%dT%H:%M:%SZ", "%Y-%m-%d"] {
+        if let Ok(tm) = time::strptime(s, format) {
+            return Some(tm.rfc3339().to_string());
+        }
+    }
+    let ago = match ago(s) {
+        Some(ago) => ago,
+        None => return None,
+    };
+    let ago = time::Duration::seconds(ago.as_secs() as i64);
+    Some(time::at_utc(time::get_time() - ago).rfc3339().to_string())
+}
@@ -1,5 +1,13 @@
 #![allow(bad_style)]
 
+use futures::Future;
+use rustc_serialize::{Decodable, Decoder};
+
+use http::{self, Session};
+use provider::{self, CiProvider, Status};
+use MyFuture;
+use Repo;
+
 #[derive(RustcDecodable, Debug)]
 pub struct List {
     pub value: Vec<Build>,
@@ -9,4 +17,254 @@ pub struct List {
 pub struct Build {
     pub id: u32,
     pub status: String,
+    pub result: Option<String>,
+    pub sourceBranch: String,
+    pub sourceVersion: String,
+    pub definition: Definition,
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct Definition {
+    pub id: u32,
+    pub name: String,
+}
+
+/// A build's timeline, made up of its stages, phases, jobs and tasks.
+#[derive(RustcDecodable, Debug)]
+pub struct Timeline {
+    pub records: Vec<Record>,
+}
+
+#[derive(Debug)]
+pub struct Record {
+    pub id: String,
+    pub parentId: Option<String>,
+    pub name: String,
+    pub type_: String,
+    pub state: Option<String>,
+    pub result: Option<String>,
+}
+
+// written out by hand as `type` can't be used as a field name
+impl Decodable for Record {
+    fn decode<D: Decoder>(d: &mut D) -> Result<Record, D::Error> {
+        d.read_struct("Record", 6, |d| {
+            Ok(Record {
+                id: try!(d.read_struct_field("id", 0, Decodable::decode)),
+                parentId: try!(d.read_struct_field("parentId", 1, Decodable::decode)),
+                name: try!(d.read_struct_field("name", 2, Decodable::decode)),
+                type_: try!(d.read_struct_field("type", 3, Decodable::decode)),
+                state: try!(d.read_struct_field("state", 4, Decodable::decode)),
+                result: try!(d.read_struct_field("result", 5, Decodable::decode)),
+            })
+        })
+    }
+}
+
+pub struct AzurePipelines {
+    pub session: Session,
+    /// Token for repositories without one of their own, if there is one.
+    pub token: Option<String>,
+    /// Root of the Azure DevOps instance, e.g. `https://dev.azure.com`.
+    pub base_url: String,
+    /// Most pages of builds to look through per check.
+    pub max_pages: usize,
+}
+
+impl AzurePipelines {
+    /// Returns the instance `repo` builds on, which may differ from the
+    /// default.
+    fn base_url(&self, repo: &Repo) -> String {
+        repo.azure
+            .as_ref()
+            .and_then(|c| c.url.clone())
+            .unwrap_or_else(|| self.base_url.clone())
+    }
+
+    /// Returns the token for `repo`'s instance, its own if it has one. It's
+    /// never empty for the repositories this covers.
+    fn token(&self, repo: &Repo) -> String {
+        repo.azure
+            .as_ref()
+            .and_then(|c| c.token.clone())
+            .or_else(|| self.token.clone())
+            .unwrap_or_default()
+    }
+}
+
+impl CiProvider for AzurePipelines {
+    fn name(&self) -> &'static str {
+        "azure_pipelines"
+    }
+
+    fn covers(&self, repo: &Repo) -> bool {
+        self.token.is_some() || repo.azure.as_ref().map_or(false, |c| c.token.is_some())
+    }
+
+    fn budget(&self, repo: &Repo) -> Option<http::Budget> {
+        self.session.budget(self.name(), &self.token(repo))
+    }
+
+    fn builds(&self, repo: &Repo, branch: Option<&str>) -> MyFuture<Vec<provider::Build>> {
+        let (organization, project) = project(repo);
+        let mut url = format!(
+            "/{}/{}/_apis/build/builds?api-version=5.0&queryOrder=queueTimeDescending",
+            organization, project,
+        );
+        if let Some(branch) = branch {
+            url.push_str(&format!("&branchName=refs/heads/{}", branch));
+        }
+        let definitions = repo.azure.as_ref().and_then(|c| c.definitions.as_ref());
+        if let Some(definitions) = definitions {
+            let ids = definitions
+                .iter()
+                .map(|id| id.to_string())
+                .collect::<Vec<_>>();
+            url.push_str(&format!("&definitions={}", ids.join(",")));
+        }
+
+        let session = self.session.clone();
+        let token = self.token(repo);
+        let base_url = self.base_url(repo);
+        provider::paginate(self.max_pages, move |continuation: Option<String>| {
+            let url = match continuation {
+                Some(continuation) => format!("{}&continuationToken={}", url, continuation),
+                None => url.clone(),
+            };
+            let history = http::azure_pipelines_list(&session, &base_url, &url, &token);
+            let page = history.map(|(list, continuation): (List, _)| {
+                let builds = list
+                    .value
+                    .iter()
+                    .map(|build| provider::Build {
+                        id: build.id.to_string(),
+                        number: build.id as u64,
+                        branch: build
+                            .sourceBranch
+                            .trim_start_matches("refs/heads/")
+                            .to_string(),
+                        commit: Some(build.sourceVersion.clone()),
+                        workflow: Some(build.definition.id.to_string()),
+                        status: build_status(build),
+                    })
+                    .collect::<Vec<_>>();
+                // older pages are only worth fetching while builds are still
+                // running on this one
+                let next = if builds.iter().any(|b| b.status.is_running()) {
+                    continuation
+                } else {
+                    None
+                };
+                (builds, next)
+            });
+            Box::new(page)
+        })
+    }
+
+    fn jobs(&self, repo: &Repo, build: &provider::Build) -> MyFuture<Vec<provider::Job>> {
+        let (organization, project) = project(repo);
+        let url = format!(
+            "/{}/{}/_apis/build/builds/{}/timeline?api-version=5.0",
+            organization, project, build.id,
+        );
+        let timeline =
+            http::azure_pipelines_get(&self.session, &self.base_url(repo), &url, &self.token(repo));
+        let jobs = timeline.map(|timeline: Timeline| {
+            let records = &timeline.records;
+            records
+                .iter()
+                .filter(|record| record.type_ == "Job" || record.type_ == "Stage")
+                .filter(|record| !continued_on_error(records, record))
+                .map(|record| provider::Job {
+                    id: format!("{} {}", record.type_.to_lowercase(), record.name),
+                    status: record_status(record),
+                })
+                .collect()
+        });
+        Box::new(jobs)
+    }
+
+    fn cancel(&self, repo: &Repo, build: &provider::Build) -> MyFuture<()> {
+        let (organization, project) = project(repo);
+        let url = format!(
+            "/{}/{}/_apis/build/builds/{}?api-version=5.0",
+            organization, project, build.id,
+        );
+        let body = "{\"status\":\"Cancelling\"}";
+        http::azure_patch(
+            &self.session,
+            &self.base_url(repo),
+            &url,
+            &self.token(repo),
+            body,
+        )
+    }
+}
+
+/// Returns the organization and project `repo` builds under, which default to
+/// its user and name.
+fn project(repo: &Repo) -> (String, String) {
+    let configured = repo.azure.as_ref();
+    let organization = configured
+        .and_then(|c| c.organization.clone())
+        .unwrap_or_else(|| repo.user.clone());
+    let project = configured
+        .and_then(|c| c.project.clone())
+        .unwrap_or_else(|| repo.name.clone());
+    (organization, project)
+}
+
+/// Completed builds only say how they finished in their `result`, so prefer
+/// that over the coarse `status` when it's available.
+fn build_status(build: &Build) -> Status {
+    match (&build.status[..], &build.result) {
+        ("completed", &Some(ref result)) => classify(result),
+        (status, _) => classify(status),
+    }
+}
+
+/// Whether `record` failed with `continueOnError` set, which Azure only
+/// shows by finishing one of its parents as `succeededWithIssues`.
+fn continued_on_error(records: &[Record], record: &Record) -> bool {
+    if result(record) != Some("failed") {
+        return false;
+    }
+    let mut parent = record.parentId.as_ref();
+    // bounded, in case the parents ever form a cycle
+    for _ in 0..records.len() {
+        let record = match parent.and_then(|id| records.iter().find(|r| &r.id == id)) {
+            Some(record) => record,
+            None => return false,
+        };
+        if result(record) == Some("succeededWithIssues") {
+            return true;
+        }
+        parent = record.parentId.as_ref();
+    }
+    false
+}
+
+fn result(record: &Record) -> Option<&str> {
+    record.result.as_ref().map(|r| &r[..])
+}
+
+/// Timeline records are like builds, but only some of them have a result.
+fn record_status(record: &Record) -> Status {
+    match (&record.state, &record.result) {
+        (_, &Some(ref result)) => classify(result),
+        (&Some(ref state), &None) => classify(state),
+        (&None, &None) => Status::Queued,
+    }
+}
+
+fn classify(status: &str) -> Status {
+    match status {
+        "notStarted" | "postponed" | "none" | "pending" => Status::Queued,
+        "completed" | "succeeded" | "partiallySucceeded" | "succeededWithIssues" | "skipped" => {
+            Status::Passed
+        }
+        "failed" => Status::Failed,
+        "cancelling" | "canceled" | "abandoned" => Status::Canceled,
+        _ => Status::Running,
+    }
 }
@@ -0,0 +1,163 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use futures::Future;
+
+use http::{self, Session};
+use provider::{self, CiProvider, Status};
+use MyFuture;
+use Repo;
+
+#[derive(RustcDecodable, Debug)]
+pub struct Build {
+    pub id: String,
+    pub number: u64,
+    pub branch: String,
+    pub commit: String,
+    pub state: String,
+    pub jobs: Vec<Job>,
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct Job {
+    pub id: String,
+    pub name: Option<String>,
+    pub state: Option<String>,
+    pub soft_failed: Option<bool>,
+}
+
+/// Where the builds of a repository live on Buildkite.
+#[derive(RustcDecodable, Clone, Debug)]
+pub struct Pipeline {
+    pub organization: String,
+    pub pipeline: String,
+}
+
+/// Buildkite pipelines aren't named after repositories, so each repository
+/// has to be mapped onto its pipeline explicitly through `Repo::buildkite`.
+/// Repositories without a pipeline are skipped.
+pub struct Buildkite {
+    pub session: Session,
+    pub token: String,
+    pub base_url: String,
+    /// Jobs of the builds listed last, keyed by their `Pipeline::build_url`,
+    /// as builds are listed along with their jobs anyway.
+    pub jobs: Rc<RefCell<HashMap<String, Vec<provider::Job>>>>,
+}
+
+impl Pipeline {
+    fn build_url(&self, id: &str) -> String {
+        format!(
+            "/organizations/{}/pipelines/{}/builds/{}",
+            self.organization, self.pipeline, id
+        )
+    }
+}
+
+impl CiProvider for Buildkite {
+    fn name(&self) -> &'static str {
+        "buildkite"
+    }
+
+    fn budget(&self, _repo: &Repo) -> Option<http::Budget> {
+        self.session.budget(self.name(), &self.token)
+    }
+
+    fn builds(&self, repo: &Repo, branch: Option<&str>) -> MyFuture<Vec<provider::Build>> {
+        let pipeline = match repo.buildkite {
+            Some(ref pipeline) => pipeline.clone(),
+            None => return Box::new(futures::finished(Vec::new())),
+        };
+        // builds with a hard-failed job are `failing` until the rest of their
+        // jobs finish, and are just the ones to cancel
+        let mut url = format!(
+            "/organizations/{}/pipelines/{}/builds\
+             ?state[]=scheduled&state[]=running&state[]=failing",
+            pipeline.organization, pipeline.pipeline
+        );
+        if let Some(branch) = branch {
+            url.push_str(&format!("&branch={}", branch));
+        }
+        let builds = http::buildkite_get(&self.session, &self.base_url, &url, &self.token);
+        let cache = self.jobs.clone();
+        let builds = builds.map(move |builds: Vec<Build>| {
+            let mut cache = cache.borrow_mut();
+            let prefix = pipeline.build_url("");
+            cache.retain(|url, _| !url.starts_with(&prefix));
+            for build in builds.iter() {
+                let url = pipeline.build_url(&build.number.to_string());
+                cache.insert(url, jobs(build));
+            }
+            builds
+                .iter()
+                .map(|build| provider::Build {
+                    id: build.number.to_string(),
+                    number: build.number,
+                    branch: build.branch.clone(),
+                    // builds triggered by hand may just say `HEAD`
+                    commit: if build.commit == "HEAD" {
+                        None
+                    } else {
+                        Some(build.commit.clone())
+                    },
+                    workflow: None,
+                    status: classify(&build.state),
+                })
+                .collect()
+        });
+        Box::new(builds)
+    }
+
+    fn jobs(&self, repo: &Repo, build: &provider::Build) -> MyFuture<Vec<provider::Job>> {
+        let pipeline = match repo.buildkite {
+            Some(ref pipeline) => pipeline,
+            None => return Box::new(futures::finished(Vec::new())),
+        };
+        let url = pipeline.build_url(&build.id);
+        if let Some(jobs) = self.jobs.borrow().get(&url) {
+            return Box::new(futures::finished(jobs.clone()));
+        }
+        let build = http::buildkite_get(&self.session, &self.base_url, &url, &self.token);
+        Box::new(build.map(|build: Build| jobs(&build)))
+    }
+
+    fn cancel(&self, repo: &Repo, build: &provider::Build) -> MyFuture<()> {
+        let pipeline = match repo.buildkite {
+            Some(ref pipeline) => pipeline,
+            None => return Box::new(futures::finished(())),
+        };
+        let url = format!("{}/cancel", pipeline.build_url(&build.id));
+        http::buildkite_put(&self.session, &self.base_url, &url, &self.token)
+    }
+}
+
+/// Leaves out soft failures, and jobs without a state, which are waiters and
+/// the like that don't run anything.
+fn jobs(build: &Build) -> Vec<provider::Job> {
+    build
+        .jobs
+        .iter()
+        .filter(|job| !job.soft_failed.unwrap_or(false))
+        .filter_map(|job| {
+            job.state.as_ref().map(|state| provider::Job {
+                id: job.name.clone().unwrap_or_else(|| job.id.clone()),
+                status: classify(state),
+            })
+        })
+        .collect()
+}
+
+fn classify(status: &str) -> Status {
+    match status {
+        "pending" | "waiting" | "blocked" | "unblocked" | "limiting" | "limited" | "scheduled"
+        | "assigned" | "accepted" => Status::Queued,
+        // a job has failed, but others are still running
+        "running" | "failing" => Status::Running,
+        "passed" | "skipped" | "not_run" | "finished" => Status::Passed,
+        "failed" | "waiting_failed" | "blocked_failed" | "unblocked_failed" | "timing_out"
+        | "timed_out" | "broken" | "expired" => Status::Failed,
+        "canceling" | "canceled" => Status::Canceled,
+        _ => Status::Running,
+    }
+}
@@ -0,0 +1,172 @@
+use futures::Future;
+
+use http::{self, Session};
+use provider::{self, CiProvider, Status};
+use MyFuture;
+use Repo;
+
+#[derive(RustcDecodable, Debug)]
+pub struct Pipelines {
+    pub items: Vec<Pipeline>,
+    pub next_page_token: Option<String>,
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct Pipeline {
+    pub id: String,
+    pub number: u64,
+    pub state: String,
+    pub vcs: Vcs,
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct Vcs {
+    pub revision: String,
+    /// Not set for pipelines triggered by tags.
+    pub branch: Option<String>,
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct Workflows {
+    pub items: Vec<Workflow>,
+    pub next_page_token: Option<String>,
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct Workflow {
+    pub id: String,
+    pub name: String,
+    pub pipeline_number: u64,
+    pub status: String,
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct Jobs {
+    pub items: Vec<Job>,
+    pub next_page_token: Option<String>,
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct Job {
+    pub name: String,
+    pub status: String,
+}
+
+/// Every pipeline costs a request for its workflows, so only the most recent
+/// ones are looked at, and fewer still once the rate limit runs low. Older
+/// pipelines are unlikely to still be running anyway.
+const MAX_PIPELINES: usize = 10;
+const MAX_PIPELINES_LOW_BUDGET: usize = 2;
+
+pub struct CircleCi {
+    pub session: Session,
+    pub token: String,
+    /// Root of the CircleCI API, e.g. `https://circleci.com/api/v2`.
+    pub base_url: String,
+}
+
+impl CiProvider for CircleCi {
+    fn name(&self) -> &'static str {
+        "circleci"
+    }
+
+    fn budget(&self, _repo: &Repo) -> Option<http::Budget> {
+        self.session.budget(self.name(), &self.token)
+    }
+
+    /// Each workflow of each recent pipeline on `branch` is reported as a
+    /// separate build numbered after its pipeline, so workflows of older
+    /// pipelines are superseded by those of the latest one.
+    fn builds(&self, repo: &Repo, branch: Option<&str>) -> MyFuture<Vec<provider::Build>> {
+        let mut url = format!("/project/{}/pipeline", project(repo));
+        if let Some(branch) = branch {
+            url.push_str(&format!("?branch={}", branch));
+        }
+        let pipelines = http::circleci_get(&self.session, &self.base_url, &url, &self.token);
+
+        let session = self.session.clone();
+        let token = self.token.clone();
+        let base_url = self.base_url.clone();
+        let builds = pipelines.and_then(move |pipelines: Pipelines| {
+            let max = match session.budget("circleci", &token) {
+                Some(budget) if budget.is_low() => {
+                    warn!(
+                        { provider: "circleci" },
+                        "only fetching workflows of {} pipeline(s) as the rate limit is low: {}",
+                        MAX_PIPELINES_LOW_BUDGET,
+                        budget
+                    );
+                    MAX_PIPELINES_LOW_BUDGET
+                }
+                _ => MAX_PIPELINES,
+            };
+            // errored pipelines never got as far as running any workflows
+            let workflows = pipelines
+                .items
+                .iter()
+                .filter(|pipeline| pipeline.state != "errored")
+                .take(max)
+                .map(|pipeline| {
+                    let url = format!("/pipeline/{}/workflow", pipeline.id);
+                    let workflows = http::circleci_get(&session, &base_url, &url, &token);
+                    let branch = pipeline.vcs.branch.clone().unwrap_or_default();
+                    let commit = pipeline.vcs.revision.clone();
+                    workflows.map(move |workflows: Workflows| {
+                        workflows
+                            .items
+                            .iter()
+                            .map(|workflow| provider::Build {
+                                id: workflow.id.clone(),
+                                number: workflow.pipeline_number,
+                                branch: branch.clone(),
+                                commit: Some(commit.clone()),
+                                workflow: None,
+                                status: classify(&workflow.status),
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect::<Vec<_>>();
+            futures::collect(workflows)
+        });
+        Box::new(builds.map(|builds| builds.into_iter().flat_map(|b| b).collect()))
+    }
+
+    fn jobs(&self, _repo: &Repo, build: &provider::Build) -> MyFuture<Vec<provider::Job>> {
+        let url = format!("/workflow/{}/job", build.id);
+        let jobs = http::circleci_get(&self.session, &self.base_url, &url, &self.token);
+        let jobs = jobs.map(|jobs: Jobs| {
+            jobs.items
+                .iter()
+                .map(|job| provider::Job {
+                    id: job.name.clone(),
+                    status: classify(&job.status),
+                })
+                .collect()
+        });
+        Box::new(jobs)
+    }
+
+    fn cancel(&self, _repo: &Repo, build: &provider::Build) -> MyFuture<()> {
+        let url = format!("/workflow/{}/cancel", build.id);
+        http::circleci_post(&self.session, &self.base_url, &url, &self.token)
+    }
+}
+
+/// Returns the project slug of `repo`, which defaults to it being on GitHub.
+fn project(repo: &Repo) -> String {
+    repo.circleci
+        .as_ref()
+        .and_then(|c| c.project.clone())
+        .unwrap_or_else(|| format!("gh/{}/{}", repo.user, repo.name))
+}
+
+fn classify(status: &str) -> Status {
+    match status {
+        "queued" | "not_running" | "on_hold" | "blocked" => Status::Queued,
+        "success" | "not_run" => Status::Passed,
+        "failed" | "error" | "infrastructure_fail" | "timedout" | "unauthorized" => Status::Failed,
+        "canceled" | "terminated-unknown" => Status::Canceled,
+        _ => Status::Running,
+    }
+}
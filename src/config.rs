@@ -0,0 +1,275 @@
+//! The `--config` file, for when one set of flags doesn't fit every
+//! repository.
+//!
+//! ```toml
+//! appveyor_account = "rust-lang"
+//! max_pages = 3
+//! audit_log = "/var/lib/cancelbot/audit.jsonl"
+//! max_body_excerpt = 200
+//! travis_url = "org"
+//!
+//! [tokens]
+//! travis = "env:TRAVIS_TOKEN"
+//! appveyor = "file:/etc/cancelbot/appveyor-token"
+//! azure_pipelines = "env:AZURE_TOKEN"
+//!
+//! [[repo]]
+//! name = "rust-lang/rust"
+//! branches = ["auto", "try"]
+//! github_heads = true
+//! providers = ["travis", "appveyor", "azure_pipelines"]
+//! appveyor = { project = "rust" }
+//! azure_pipelines = { organization = "rust-lang", project = "rust", definitions = [1] }
+//!
+//! [[repo]]
+//! name = "acme/widgets"
+//! travis = { url = "https://travis.acme.example/api", token = "env:ACME_TRAVIS_TOKEN" }
+//!
+//! [repo.azure_pipelines]
+//! url = "https://tfs.acme.example/tfs"
+//! token = "file:/etc/cancelbot/acme-azure-token"
+//! organization = "Default"
+//!
+//! [[repo]]
+//! name = "rust-lang/cargo"
+//! checkout = "/srv/cancelbot/cargo"
+//! providers = ["buildkite"]
+//! buildkite = { organization = "rust-lang", pipeline = "cargo" }
+//!
+//! [[repo]]
+//! name = "acme/gadgets"
+//! circleci = { project = "bb/acme/gadgets" }
+//! ```
+//!
+//! A repository's `providers` are named `travis`, `appveyor`,
+//! `azure_pipelines`, `github`, `gitlab`, `circleci` or `buildkite`, and the
+//! settings specific to one of them go in the table of the same name.
+//!
+//! Builds are superseded by builds of the branch's head commit, which is
+//! fetched from `origin` into the repository's `checkout` if it has one, or
+//! looked up on GitHub if it sets `github_heads` (or the global
+//! `github_heads` is set) and a GitHub token is given. Otherwise only build
+//! numbers decide which build is the latest, as a mirror on GitHub may well
+//! lag behind the repository itself.
+//!
+//! Tokens are references rather than the secrets themselves: `env:NAME`
+//! reads an environment variable and `file:PATH` the (trimmed) contents of a
+//! file. Anything else is taken literally.
+//!
+//! The `*_url`s point at self-hosted installations; Travis additionally
+//! accepts `org` and `com` for travis-ci.org and travis-ci.com. They can be
+//! set for all repositories at the top and overridden per repository, in
+//! which case the repository also needs a `token` for its installation, as
+//! the global tokens are only ever sent to the global urls. A repository's
+//! own token is enough for it to be checked, even without a global token,
+//! which the repositories lacking one of their own then aren't.
+
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use rustc_serialize::Decodable;
+use toml;
+
+use buildkite;
+use errors::*;
+
+/// Names of all the providers, as in `CiProvider::name`.
+pub static PROVIDERS: &[&str] = &[
+    "travis",
+    "appveyor",
+    "azure_pipelines",
+    "github",
+    "gitlab",
+    "circleci",
+    "buildkite",
+];
+
+#[derive(RustcDecodable, Debug, Default)]
+pub struct Config {
+    /// AppVeyor account for repositories which don't name their own.
+    pub appveyor_account: Option<String>,
+    pub travis_url: Option<String>,
+    pub appveyor_url: Option<String>,
+    pub azure_url: Option<String>,
+    pub gitlab_url: Option<String>,
+    pub buildkite_url: Option<String>,
+    pub circleci_url: Option<String>,
+    /// Used unless `--max-pages` is given.
+    pub max_pages: Option<usize>,
+    /// Used unless `--build-minutes` is given.
+    pub build_minutes: Option<f64>,
+    /// Used unless `--audit-log` is given.
+    pub audit_log: Option<String>,
+    /// Used unless `--max-body-excerpt` is given.
+    pub max_body_excerpt: Option<usize>,
+    /// Default for repositories' `github_heads`; `--github-heads` sets it.
+    pub github_heads: Option<bool>,
+    pub tokens: Option<Tokens>,
+    pub repo: Option<Vec<Repo>>,
+}
+
+#[derive(RustcDecodable, Debug, Default)]
+pub struct Tokens {
+    pub travis: Option<String>,
+    pub appveyor: Option<String>,
+    pub azure_pipelines: Option<String>,
+    pub github: Option<String>,
+    pub gitlab: Option<String>,
+    pub circleci: Option<String>,
+    pub buildkite: Option<String>,
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct Repo {
+    /// `user/name`, as on the command line.
+    pub name: String,
+    /// Branches, or glob patterns of branches, to check. Defaults to
+    /// `--branch`.
+    pub branches: Option<Vec<String>>,
+    /// Names of the providers this repository builds on, defaulting to all of
+    /// the ones with a token.
+    pub providers: Option<Vec<String>>,
+    pub travis: Option<Travis>,
+    pub appveyor: Option<AppVeyor>,
+    pub azure_pipelines: Option<Azure>,
+    pub buildkite: Option<buildkite::Pipeline>,
+    pub circleci: Option<CircleCi>,
+    /// Local clone of the repository, to look up branch heads in.
+    pub checkout: Option<String>,
+    /// Whether the repository is hosted on GitHub, so that branch heads can
+    /// be looked up there.
+    pub github_heads: Option<bool>,
+}
+
+#[derive(RustcDecodable, Clone, Debug)]
+pub struct Travis {
+    /// Defaults to the global `travis_url`.
+    pub url: Option<String>,
+    /// Reference to the token for `url`, required along with it.
+    pub token: Option<String>,
+}
+
+#[derive(RustcDecodable, Clone, Debug)]
+pub struct AppVeyor {
+    /// Defaults to the global `appveyor_url`.
+    pub url: Option<String>,
+    /// Reference to the token for `url`, required along with it.
+    pub token: Option<String>,
+    /// Defaults to the global `appveyor_account`.
+    pub account: Option<String>,
+    /// Project slug, defaulting to the repository's name.
+    pub project: Option<String>,
+}
+
+#[derive(RustcDecodable, Clone, Debug)]
+pub struct Azure {
+    /// Defaults to the global `azure_url`.
+    pub url: Option<String>,
+    /// Reference to the token for `url`, required along with it.
+    pub token: Option<String>,
+    /// Defaults to the repository's user.
+    pub organization: Option<String>,
+    /// Defaults to the repository's name.
+    pub project: Option<String>,
+    /// Only consider builds of these pipeline definitions.
+    pub definitions: Option<Vec<u32>>,
+}
+
+#[derive(RustcDecodable, Clone, Debug)]
+pub struct CircleCi {
+    /// Project slug, `vcs/owner/name` like `bb/acme/widgets` for Bitbucket,
+    /// defaulting to the repository on GitHub.
+    pub project: Option<String>,
+}
+
+pub fn load(path: &Path) -> BorsResult<Config> {
+    let mut contents = String::new();
+    try!(File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut contents))
+        .chain_err(|| format!("failed to read {}", path.display())));
+
+    let mut parser = toml::Parser::new(&contents);
+    let table = match parser.parse() {
+        Some(table) => table,
+        None => {
+            let error = &parser.errors[0];
+            let (line, col) = parser.to_linecol(error.lo);
+            return Err(format!(
+                "failed to parse {}:{}:{}: {}",
+                path.display(),
+                line + 1,
+                col + 1,
+                error
+            )
+            .into());
+        }
+    };
+
+    let mut decoder = toml::Decoder::new(toml::Value::Table(table));
+    let config = try!(Config::decode(&mut decoder).map_err(|e| format!(
+        "invalid config {}: {}",
+        path.display(),
+        e
+    )));
+
+    for repo in config.repo.iter().flat_map(|r| r) {
+        // a typo would otherwise quietly stop the repository from being
+        // checked
+        for provider in repo.providers.iter().flat_map(|p| p) {
+            if !PROVIDERS.contains(&&provider[..]) {
+                return Err(format!(
+                    "invalid config {}: unknown provider `{}` for {}, expected one of {}",
+                    path.display(),
+                    provider,
+                    repo.name,
+                    PROVIDERS.join(", ")
+                )
+                .into());
+            }
+        }
+
+        // the global tokens are only for the global urls
+        let overrides = [
+            ("travis", repo.travis.as_ref().map(|c| (&c.url, &c.token))),
+            (
+                "appveyor",
+                repo.appveyor.as_ref().map(|c| (&c.url, &c.token)),
+            ),
+            (
+                "azure_pipelines",
+                repo.azure_pipelines.as_ref().map(|c| (&c.url, &c.token)),
+            ),
+        ];
+        for &(provider, settings) in overrides.iter() {
+            if let Some((&Some(_), &None)) = settings {
+                return Err(format!(
+                    "invalid config {}: {} sets its own {} url, so it needs a token for it too",
+                    path.display(),
+                    repo.name,
+                    provider
+                )
+                .into());
+            }
+        }
+    }
+    Ok(config)
+}
+
+/// Resolves a token reference from the config file, see the module docs.
+pub fn token(reference: &str) -> BorsResult<String> {
+    if reference.starts_with("env:") {
+        let name = &reference["env:".len()..];
+        env::var(name).chain_err(|| format!("failed to read token from ${}", name))
+    } else if reference.starts_with("file:") {
+        let path = &reference["file:".len()..];
+        let mut token = String::new();
+        try!(File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut token))
+            .chain_err(|| format!("failed to read token from {}", path)));
+        Ok(token.trim().to_string())
+    } else {
+        Ok(reference.to_string())
+    }
+}
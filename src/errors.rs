@@ -4,6 +4,7 @@ use std::io;
 use std::str;
 
 use curl;
+use hyper;
 use rustc_serialize::json;
 use tokio_curl;
 
@@ -18,5 +19,53 @@ error_chain! {
         json::DecoderError, Json;
         str::Utf8Error, NotUtf8;
         io::Error, Io;
+        hyper::Error, Hyper;
+    }
+
+    errors {
+        Status(provider: &'static str, url: String, code: u32, body: String) {
+            description("unexpected HTTP status")
+            display("{} responded to {} with {}: {}", provider, url, code, body)
+        }
+        Request(provider: &'static str, url: String) {
+            description("failed to set up an HTTP request")
+            display("failed to set up the request to {} for {}", url, provider)
+        }
+        Decode(provider: &'static str, url: String, body: String) {
+            description("failed to decode a response")
+            display("failed to decode the response from {} for {}: {}", url, provider, body)
+        }
+        InvalidBuild(provider: &'static str, repo: String, build: String, problem: String) {
+            description("invalid build")
+            display("{} build {} of {} is invalid: {}", provider, build, repo, problem)
+        }
+        InvalidRepo(name: String) {
+            description("invalid repository name")
+            display("repository `{}` isn't `user/name`", name)
+        }
+        InvalidPipeline(arg: String) {
+            description("invalid Buildkite pipeline")
+            display("--buildkite-pipeline `{}` isn't `user/name=organization/pipeline`", arg)
+        }
+        InvalidPattern(pattern: String, problem: String) {
+            description("invalid branch pattern")
+            display("invalid branch pattern `{}`: {}", pattern, problem)
+        }
+        NoAccount(provider: &'static str, repo: String) {
+            description("no account to look up builds under")
+            display("no {} account for {}", provider, repo)
+        }
+        RetryTooLate(provider: &'static str, secs: u64) {
+            description("retry would take too long")
+            display("not waiting {}s to retry the request to {}", secs, provider)
+        }
+        Git(path: String, command: String, stderr: String) {
+            description("git failed")
+            display("`git {}` failed in {}: {}", command, path, stderr)
+        }
+        Check(provider: &'static str, repo: String, branch: String) {
+            description("failed to check builds")
+            display("failed to check {} {} on {}", repo, branch, provider)
+        }
     }
 }
@@ -0,0 +1,145 @@
+use futures::Future;
+
+use http::{self, Session};
+use provider::{self, CiProvider, Status};
+use MyFuture;
+use Repo;
+
+#[derive(RustcDecodable, Debug)]
+pub struct WorkflowRuns {
+    pub total_count: u32,
+    pub workflow_runs: Vec<WorkflowRun>,
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct WorkflowRun {
+    pub id: u64,
+    pub run_number: u64,
+    pub workflow_id: u64,
+    pub head_branch: String,
+    pub head_sha: String,
+    pub status: String,
+    pub conclusion: Option<String>,
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct Jobs {
+    pub total_count: u32,
+    pub jobs: Vec<Job>,
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct Job {
+    pub id: u64,
+    pub run_id: u64,
+    pub name: String,
+    pub status: String,
+    pub conclusion: Option<String>,
+}
+
+/// Jobs are listed this many to a page, the most GitHub allows.
+const JOBS_PER_PAGE: u32 = 100;
+
+/// Most pages of jobs to fetch per run, which no sane matrix gets near.
+const MAX_JOB_PAGES: usize = 10;
+
+pub struct GitHubActions {
+    pub session: Session,
+    pub token: String,
+}
+
+impl CiProvider for GitHubActions {
+    fn name(&self) -> &'static str {
+        "github"
+    }
+
+    fn budget(&self, _repo: &Repo) -> Option<http::Budget> {
+        self.session.budget(self.name(), &self.token)
+    }
+
+    fn builds(&self, repo: &Repo, branch: Option<&str>) -> MyFuture<Vec<provider::Build>> {
+        // `branch` also matches pull requests from forks with a branch of the
+        // same name, which are none of our business
+        let mut url = format!(
+            "/repos/{}/{}/actions/runs?per_page=30&event=push",
+            repo.user, repo.name
+        );
+        if let Some(branch) = branch {
+            url.push_str(&format!("&branch={}", branch));
+        }
+        let runs = http::github_get(&self.session, &url, &self.token);
+        let builds = runs.map(|runs: WorkflowRuns| {
+            runs.workflow_runs
+                .iter()
+                .map(|run| provider::Build {
+                    id: run.id.to_string(),
+                    number: run.run_number,
+                    branch: run.head_branch.clone(),
+                    commit: Some(run.head_sha.clone()),
+                    workflow: Some(run.workflow_id.to_string()),
+                    status: status(&run.status, &run.conclusion),
+                })
+                .collect()
+        });
+        Box::new(builds)
+    }
+
+    fn jobs(&self, repo: &Repo, build: &provider::Build) -> MyFuture<Vec<provider::Job>> {
+        let base = format!(
+            "/repos/{}/{}/actions/runs/{}/jobs?per_page={}",
+            repo.user, repo.name, build.id, JOBS_PER_PAGE
+        );
+        let session = self.session.clone();
+        let token = self.token.clone();
+        provider::paginate(MAX_JOB_PAGES, move |page: Option<u32>| {
+            let page = page.unwrap_or(1);
+            let url = format!("{}&page={}", base, page);
+            let jobs = http::github_get(&session, &url, &token);
+            let jobs = jobs.map(move |jobs: Jobs| {
+                let next = if page * JOBS_PER_PAGE < jobs.total_count {
+                    Some(page + 1)
+                } else {
+                    None
+                };
+                let jobs = jobs
+                    .jobs
+                    .iter()
+                    .map(|job| provider::Job {
+                        id: job.name.clone(),
+                        status: status(&job.status, &job.conclusion),
+                    })
+                    .collect();
+                (jobs, next)
+            });
+            Box::new(jobs) as MyFuture<_>
+        })
+    }
+
+    fn cancel(&self, repo: &Repo, build: &provider::Build) -> MyFuture<()> {
+        let url = format!(
+            "/repos/{}/{}/actions/runs/{}/cancel",
+            repo.user, repo.name, build.id
+        );
+        http::github_post(&self.session, &url, &self.token)
+    }
+}
+
+/// Runs and jobs only report how they finished through `conclusion` once
+/// their `status` is `completed`.
+fn status(status: &str, conclusion: &Option<String>) -> Status {
+    match (status, conclusion) {
+        ("completed", &Some(ref conclusion)) => classify(conclusion),
+        (status, _) => classify(status),
+    }
+}
+
+fn classify(status: &str) -> Status {
+    match status {
+        // `action_required` runs are waiting for someone to approve them
+        "queued" | "waiting" | "requested" | "pending" | "action_required" => Status::Queued,
+        "completed" | "success" | "neutral" | "skipped" => Status::Passed,
+        "failure" | "timed_out" | "startup_failure" => Status::Failed,
+        "cancelled" | "stale" => Status::Canceled,
+        _ => Status::Running,
+    }
+}
@@ -0,0 +1,134 @@
+use futures::Future;
+use rustc_serialize::{Decodable, Decoder};
+
+use http::{self, Session};
+use provider::{self, CiProvider, Status};
+use MyFuture;
+use Repo;
+
+#[derive(Debug)]
+pub struct Pipeline {
+    pub id: u64,
+    pub iid: u64,
+    pub ref_: String,
+    pub sha: String,
+    pub status: String,
+}
+
+// written out by hand as `ref` can't be used as a field name
+impl Decodable for Pipeline {
+    fn decode<D: Decoder>(d: &mut D) -> Result<Pipeline, D::Error> {
+        d.read_struct("Pipeline", 5, |d| {
+            Ok(Pipeline {
+                id: try!(d.read_struct_field("id", 0, Decodable::decode)),
+                iid: try!(d.read_struct_field("iid", 1, Decodable::decode)),
+                ref_: try!(d.read_struct_field("ref", 2, Decodable::decode)),
+                sha: try!(d.read_struct_field("sha", 3, Decodable::decode)),
+                status: try!(d.read_struct_field("status", 4, Decodable::decode)),
+            })
+        })
+    }
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct Job {
+    pub id: u64,
+    pub name: String,
+    pub stage: String,
+    pub status: String,
+    pub allow_failure: bool,
+}
+
+pub struct GitLab {
+    pub session: Session,
+    pub token: String,
+    /// Root of the GitLab instance, e.g. `https://gitlab.com`.
+    pub base_url: String,
+}
+
+impl CiProvider for GitLab {
+    fn name(&self) -> &'static str {
+        "gitlab"
+    }
+
+    fn budget(&self, _repo: &Repo) -> Option<http::Budget> {
+        self.session.budget(self.name(), &self.token)
+    }
+
+    fn builds(&self, repo: &Repo, branch: Option<&str>) -> MyFuture<Vec<provider::Build>> {
+        let mut url = format!("/projects/{}/pipelines?per_page=20", project(repo));
+        if let Some(branch) = branch {
+            url.push_str(&format!("&ref={}", branch));
+        }
+        let pipelines = http::gitlab_get(&self.session, &self.base_url, &url, &self.token);
+        let builds = pipelines.map(|pipelines: Vec<Pipeline>| {
+            pipelines
+                .iter()
+                .map(|pipeline| provider::Build {
+                    id: pipeline.id.to_string(),
+                    number: pipeline.id,
+                    branch: pipeline.ref_.clone(),
+                    commit: Some(pipeline.sha.clone()),
+                    workflow: None,
+                    status: classify(&pipeline.status),
+                })
+                .collect()
+        });
+        Box::new(builds)
+    }
+
+    fn jobs(&self, repo: &Repo, build: &provider::Build) -> MyFuture<Vec<provider::Job>> {
+        let url = format!(
+            "/projects/{}/pipelines/{}/jobs?per_page=100",
+            project(repo),
+            build.id
+        );
+        let jobs = http::gitlab_get(&self.session, &self.base_url, &url, &self.token);
+        let jobs = jobs.map(|jobs: Vec<Job>| {
+            // leave out jobs with `allow_failure: true`
+            jobs.iter()
+                .filter(|job| !job.allow_failure)
+                .map(|job| provider::Job {
+                    id: job.name.clone(),
+                    status: classify(&job.status),
+                })
+                .collect()
+        });
+        Box::new(jobs)
+    }
+
+    fn cancel(&self, repo: &Repo, build: &provider::Build) -> MyFuture<()> {
+        let url = format!("/projects/{}/pipelines/{}/cancel", project(repo), build.id);
+        http::gitlab_post(&self.session, &self.base_url, &url, &self.token)
+    }
+}
+
+/// GitLab identifies projects by their full path, including any nested
+/// groups, with the path itself URL-encoded.
+///
+/// Nested groups are passed on the command line as `group/subgroup/project`,
+/// which ends up split between `Repo::user` and `Repo::name`.
+fn project(repo: &Repo) -> String {
+    let path = format!("{}/{}", repo.user, repo.name);
+    let mut encoded = String::new();
+    for b in path.bytes() {
+        match b {
+            b'-' | b'.' | b'_' | b'~' => encoded.push(b as char),
+            b if b.is_ascii_alphanumeric() => encoded.push(b as char),
+            b => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+fn classify(status: &str) -> Status {
+    match status {
+        "created" | "waiting_for_resource" | "preparing" | "pending" | "scheduled" => {
+            Status::Queued
+        }
+        "success" | "skipped" | "manual" => Status::Passed,
+        "failed" => Status::Failed,
+        "canceled" => Status::Canceled,
+        _ => Status::Running,
+    }
+}
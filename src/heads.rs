@@ -0,0 +1,87 @@
+//! Finding the commit a branch currently points at, which is the only commit
+//! still worth building on it.
+
+use std::path::Path;
+use std::process::Command;
+use std::thread;
+
+use futures::sync::oneshot;
+use futures::Future;
+
+use errors::*;
+use http::{self, Session};
+use MyFuture;
+use Repo;
+
+#[derive(RustcDecodable, Debug)]
+pub struct Branch {
+    pub commit: Commit,
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct Commit {
+    pub sha: String,
+}
+
+pub struct Heads {
+    pub session: Session,
+    pub github_token: Option<String>,
+}
+
+impl Heads {
+    /// Resolves the head of `branch` in `repo`, from the repository's local
+    /// checkout if it has one and through the GitHub API if it's hosted
+    /// there. `None` if neither is available.
+    pub fn head(&self, repo: &Repo, branch: &str) -> MyFuture<Option<String>> {
+        if let Some(ref path) = repo.checkout {
+            // fetching may take a while, which mustn't hold up everything
+            // else on the event loop
+            let (tx, rx) = oneshot::channel();
+            let (path, branch) = (path.clone(), branch.to_string());
+            thread::spawn(move || {
+                let _ = tx.send(checkout_head(&path, &branch));
+            });
+            return Box::new(rx.then(|head| match head {
+                Ok(head) => head.map(Some),
+                Err(e) => Err(e).chain_err(|| "git exited without a result"),
+            }));
+        }
+        // a mirror on GitHub may lag behind, and going by it would cancel
+        // the builds of newer commits as outdated
+        let token = match self.github_token {
+            Some(ref token) if repo.github_heads => token,
+            _ => return Box::new(futures::finished(None)),
+        };
+        let url = format!("/repos/{}/{}/branches/{}", repo.user, repo.name, branch);
+        let branch = http::github_get(&self.session, &url, token);
+        Box::new(branch.map(|branch: Branch| Some(branch.commit.sha)))
+    }
+}
+
+fn checkout_head(path: &Path, branch: &str) -> BorsResult<String> {
+    // checkouts kept around for this are rarely up to date, and going by a
+    // stale head would cancel the newest builds as outdated
+    let tracking = format!("refs/remotes/origin/{}", branch);
+    let refspec = format!("+refs/heads/{}:{}", branch, tracking);
+    try!(git(path, &["fetch", "--quiet", "origin", &refspec]));
+    git(
+        path,
+        &["rev-parse", "--verify", &format!("{}^{{commit}}", tracking)],
+    )
+}
+
+/// Runs git in `path`, returning its trimmed output.
+fn git(path: &Path, args: &[&str]) -> BorsResult<String> {
+    let output = try!(Command::new("git")
+        .arg("-C")
+        .arg(path)
+        .args(args)
+        .output()
+        .chain_err(|| "failed to run git"));
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        let kind = BorsErrorKind::Git(path.display().to_string(), args.join(" "), stderr);
+        return Err(kind.into());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
@@ -1,20 +1,267 @@
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
 use std::str;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
+use curl;
 use curl::easy::{Easy, List};
+use futures::future::{self, Loop};
 use futures::Future;
 use rustc_serialize::json;
 use rustc_serialize::Decodable;
-use tokio_curl::Session;
+use time;
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_curl;
 
 use errors::*;
+use log;
+use metrics::Metrics;
+use redact;
 use MyFuture;
 
-static TRAVIS_API_BASE: &str = "https://api.travis-ci.com";
-static APPVEYOR_API_BASE: &str = "https://ci.appveyor.com/api";
-static AZURE_API_BASE: &str = "https://dev.azure.com";
+/// Default roots of the services which can also be self-hosted.
+pub static TRAVIS_URL: &str = "https://api.travis-ci.com";
+pub static APPVEYOR_URL: &str = "https://ci.appveyor.com";
+pub static AZURE_URL: &str = "https://dev.azure.com";
+pub static BUILDKITE_URL: &str = "https://api.buildkite.com/v2";
+pub static CIRCLECI_URL: &str = "https://circleci.com/api/v2";
+static GITHUB_API_BASE: &str = "https://api.github.com";
 static AGENT: &str = "User-Agent: cancelbot (github.com/alexcrichton/cancelbot)";
 
+/// Waiting any longer than this for a retry would run into the timeout of
+/// the whole check, so such requests fail right away instead.
+const MAX_RETRY_WAIT: Duration = Duration::from_secs(20);
+
+static TRAVIS_RETRY: Retry = Retry {
+    provider: "travis",
+    attempts: 3,
+    base_ms: 1000,
+    reset: Reset::At,
+    account: None,
+};
+// AppVeyor hands out the odd 502 even when it's otherwise fine
+static APPVEYOR_RETRY: Retry = Retry {
+    provider: "appveyor",
+    attempts: 5,
+    base_ms: 1000,
+    reset: Reset::At,
+    account: None,
+};
+static AZURE_RETRY: Retry = Retry {
+    provider: "azure_pipelines",
+    attempts: 3,
+    base_ms: 1000,
+    reset: Reset::At,
+    account: None,
+};
+static GITHUB_RETRY: Retry = Retry {
+    provider: "github",
+    attempts: 3,
+    base_ms: 1000,
+    reset: Reset::At,
+    account: None,
+};
+static GITLAB_RETRY: Retry = Retry {
+    provider: "gitlab",
+    attempts: 3,
+    base_ms: 1000,
+    reset: Reset::At,
+    account: None,
+};
+static CIRCLECI_RETRY: Retry = Retry {
+    provider: "circleci",
+    attempts: 3,
+    base_ms: 1000,
+    reset: Reset::At,
+    account: None,
+};
+static BUILDKITE_RETRY: Retry = Retry {
+    provider: "buildkite",
+    attempts: 3,
+    base_ms: 1000,
+    reset: Reset::In,
+    account: None,
+};
+
+/// The HTTP session requests are made through, along with the event loop
+/// retries are scheduled on and where to record how requests went.
+#[derive(Clone)]
+pub struct Session {
+    curl: tokio_curl::Session,
+    handle: Handle,
+    /// Rate limits are per token, so budgets are per provider and token.
+    budgets: Rc<RefCell<HashMap<(&'static str, Option<u64>), Budget>>>,
+    metrics: Rc<Metrics>,
+}
+
+impl Session {
+    pub fn new(handle: Handle, metrics: Rc<Metrics>) -> Session {
+        Session {
+            curl: tokio_curl::Session::new(handle.clone()),
+            handle: handle,
+            budgets: Rc::new(RefCell::new(HashMap::new())),
+            metrics: metrics,
+        }
+    }
+
+    /// What's left of `provider`'s rate limit for `token`, if it has told
+    /// us.
+    pub fn budget(&self, provider: &'static str, token: &str) -> Option<Budget> {
+        let key = (provider, Some(account(token)));
+        self.budgets.borrow().get(&key).cloned()
+    }
+
+    /// Every known budget, ordered by provider.
+    pub fn budgets(&self) -> Vec<(&'static str, Budget)> {
+        let mut budgets = self
+            .budgets
+            .borrow()
+            .iter()
+            .map(|(&(name, _), budget)| (name, *budget))
+            .collect::<Vec<_>>();
+        budgets.sort_by_key(|&(name, _)| name);
+        budgets
+    }
+
+    fn record_budget(&self, retry: &Retry, code: u32, response: &Response) {
+        let header = |name: &str| {
+            response
+                .header(&format!("X-RateLimit-{}", name))
+                .or_else(|| response.header(&format!("RateLimit-{}", name)))
+                .and_then(|s| s.parse().ok())
+        };
+        let budget = match header("Remaining") {
+            Some(remaining) => Budget {
+                remaining: remaining as u64,
+                limit: header("Limit").map(|l| l as u64),
+                reset: header("Reset").map(|reset| retry.reset.epoch(reset)),
+            },
+            // providers without rate limit headers still say when we've run
+            // out, so at least remember that
+            None if code == 429 => Budget {
+                remaining: 0,
+                limit: None,
+                reset: response
+                    .retry_after(retry.reset)
+                    .map(|wait| time::get_time().sec + wait.as_secs() as i64),
+            },
+            None => return,
+        };
+        self.metrics.set(
+            "cancelbot_rate_limit_remaining",
+            &[("provider", retry.provider)],
+            budget.remaining as f64,
+        );
+        let key = (retry.provider, retry.account);
+        self.budgets.borrow_mut().insert(key, budget);
+    }
+}
+
+/// What's left of a provider's rate limit, as last reported by it.
+#[derive(Clone, Copy, Debug)]
+pub struct Budget {
+    pub remaining: u64,
+    pub limit: Option<u64>,
+    /// When the limit resets, in seconds since the epoch.
+    pub reset: Option<i64>,
+}
+
+impl Budget {
+    /// Whether the budget is down to its last 10% (or 10 requests, if we
+    /// don't know the limit) and hasn't been reset since.
+    pub fn is_low(&self) -> bool {
+        if let Some(reset) = self.reset {
+            if reset <= time::get_time().sec {
+                return false;
+            }
+        }
+        let threshold = self.limit.map_or(10, |limit| limit / 10);
+        self.remaining <= threshold
+    }
+}
+
+impl fmt::Display for Budget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{}", self.remaining));
+        if let Some(limit) = self.limit {
+            try!(write!(f, "/{}", limit));
+        }
+        try!(write!(f, " requests left"));
+        if let Some(reset) = self.reset {
+            let secs = cmp::max(reset - time::get_time().sec, 0);
+            try!(write!(f, ", resets in {}m{}s", secs / 60, secs % 60));
+        }
+        Ok(())
+    }
+}
+
+/// How persistent to be with the requests to a provider.
+#[derive(Clone)]
+pub struct Retry {
+    /// Name of the provider, as in `CiProvider::name`, whose rate limit the
+    /// requests count against.
+    pub provider: &'static str,
+    /// Total number of attempts, including the first one.
+    pub attempts: u32,
+    /// Delay before the first retry, doubled for each one after that.
+    pub base_ms: u64,
+    /// How the provider says when its rate limit resets.
+    pub reset: Reset,
+    /// Hash of the token the requests are made with, if any, as each token
+    /// has a rate limit of its own.
+    pub account: Option<u64>,
+}
+
+impl Retry {
+    /// The same, for requests made with `token`.
+    fn with_token(&self, token: &str) -> Retry {
+        Retry {
+            account: Some(account(token)),
+            ..self.clone()
+        }
+    }
+
+    /// Exponential backoff, plus up to as much again of jitter so that
+    /// requests failing together don't all come back at once.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self.base_ms << (attempt - 1);
+        let jitter = time::precise_time_ns() / 1_000_000 % cmp::max(delay, 1);
+        Duration::from_millis(delay + jitter)
+    }
+}
+
+/// Tells tokens apart without keeping them around.
+fn account(token: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    token.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// How a provider's `RateLimit-Reset` header says when the limit resets.
+#[derive(Clone, Copy)]
+pub enum Reset {
+    /// In seconds since the epoch.
+    At,
+    /// In seconds from now, as Buildkite does.
+    In,
+}
+
+impl Reset {
+    /// Converts the header's value to seconds since the epoch.
+    fn epoch(&self, reset: i64) -> i64 {
+        match *self {
+            Reset::At => reset,
+            Reset::In => time::get_time().sec + reset,
+        }
+    }
+}
+
 #[allow(dead_code)]
 pub struct Response {
     easy: Easy,
@@ -22,29 +269,82 @@ pub struct Response {
     body: Arc<Mutex<Vec<u8>>>,
 }
 
-pub fn travis_get<T>(sess: &Session, url: &str, token: &str) -> MyFuture<T>
+impl Response {
+    /// How long the server wants us to wait before sending another request,
+    /// from either `Retry-After` or the reset time of an exhausted rate
+    /// limit.
+    fn retry_after(&self, reset: Reset) -> Option<Duration> {
+        if let Some(secs) = self.header("Retry-After").and_then(|s| s.parse().ok()) {
+            return Some(Duration::from_secs(secs));
+        }
+        let remaining = self
+            .header("X-RateLimit-Remaining")
+            .or_else(|| self.header("RateLimit-Remaining"));
+        if remaining != Some("0".to_string()) {
+            return None;
+        }
+        let reset = self
+            .header("X-RateLimit-Reset")
+            .or_else(|| self.header("RateLimit-Reset"))
+            .and_then(|s| s.parse::<i64>().ok())
+            .map(|value| reset.epoch(value));
+        reset.map(|reset| Duration::from_secs(cmp::max(reset - time::get_time().sec, 0) as u64))
+    }
+
+    /// Returns the value of the header `name`, compared case-insensitively.
+    pub fn header(&self, name: &str) -> Option<String> {
+        let headers = self.headers.lock().unwrap();
+        headers
+            .iter()
+            .filter_map(|h| str::from_utf8(h).ok())
+            .find_map(|h| {
+                let mut parts = h.splitn(2, ':');
+                match (parts.next(), parts.next()) {
+                    (Some(key), Some(value)) if key.trim().eq_ignore_ascii_case(name) => {
+                        Some(value.trim().to_string())
+                    }
+                    _ => None,
+                }
+            })
+    }
+}
+
+pub fn travis_get<T>(sess: &Session, base: &str, url: &str, token: &str) -> MyFuture<T>
 where
     T: Decodable + 'static,
 {
-    let url = format!("{}{}", TRAVIS_API_BASE, url);
+    let url = format!("{}{}", base, url);
     let headers = vec![
         format!("Authorization: token {}", token),
-        format!("Accept: application/vnd.travis-ci.2+json"),
+        format!("Travis-API-Version: 3"),
     ];
-    get_json(sess, &url, &headers)
+    get_json(sess, &url, &headers, &TRAVIS_RETRY.with_token(token))
+}
+
+/// Fetches something from the Travis API which needs no token.
+pub fn travis_get_public<T>(sess: &Session, base: &str, url: &str) -> MyFuture<T>
+where
+    T: Decodable + 'static,
+{
+    get_json(sess, &format!("{}{}", base, url), &[], &TRAVIS_RETRY)
 }
 
-pub fn travis_post(sess: &Session, url: &str, token: &str) -> MyFuture<()> {
+pub fn travis_post(sess: &Session, base: &str, url: &str, token: &str) -> MyFuture<()> {
     let headers = vec![
         format!("Authorization: token {}", token),
-        format!("Accept: application/vnd.travis-ci.2+json"),
+        format!("Travis-API-Version: 3"),
     ];
 
-    let response = post(sess, &format!("{}{}", TRAVIS_API_BASE, url), &headers);
+    let response = post(
+        sess,
+        &format!("{}{}", base, url),
+        &headers,
+        &TRAVIS_RETRY.with_token(token),
+    );
     Box::new(response.map(|_| ()))
 }
 
-pub fn appveyor_get<T>(sess: &Session, url: &str, token: &str) -> MyFuture<T>
+pub fn appveyor_get<T>(sess: &Session, base: &str, url: &str, token: &str) -> MyFuture<T>
 where
     T: Decodable + 'static,
 {
@@ -53,20 +353,55 @@ where
         format!("Accept: application/json"),
     ];
 
-    get_json(sess, &format!("{}{}", APPVEYOR_API_BASE, url), &headers)
+    get_json(
+        sess,
+        &format!("{}/api{}", base, url),
+        &headers,
+        &APPVEYOR_RETRY.with_token(token),
+    )
 }
 
-pub fn appveyor_delete(sess: &Session, url: &str, token: &str) -> MyFuture<()> {
+pub fn appveyor_delete(sess: &Session, base: &str, url: &str, token: &str) -> MyFuture<()> {
     let headers = vec![
         format!("Authorization: Bearer {}", token),
         format!("Accept: application/json"),
     ];
 
-    let response = delete(sess, &format!("{}{}", APPVEYOR_API_BASE, url), &headers);
+    let response = delete(
+        sess,
+        &format!("{}/api{}", base, url),
+        &headers,
+        &APPVEYOR_RETRY.with_token(token),
+    );
     Box::new(response.map(|_| ()))
 }
 
-pub fn azure_pipelines_get<T>(sess: &Session, url: &str, token: &str) -> MyFuture<T>
+pub fn azure_pipelines_get<T>(sess: &Session, base: &str, url: &str, token: &str) -> MyFuture<T>
+where
+    T: Decodable + 'static,
+{
+    let base64 = base64::encode(&format!(":{}", token));
+    let headers = vec![
+        format!("Authorization: Basic {}", base64),
+        format!("Accept: application/json"),
+    ];
+
+    get_json(
+        sess,
+        &format!("{}{}", base, url),
+        &headers,
+        &AZURE_RETRY.with_token(token),
+    )
+}
+
+/// Like `azure_pipelines_get`, but also returns the continuation token to
+/// fetch the next page of results with, if there is one.
+pub fn azure_pipelines_list<T>(
+    sess: &Session,
+    base: &str,
+    url: &str,
+    token: &str,
+) -> MyFuture<(T, Option<String>)>
 where
     T: Decodable + 'static,
 {
@@ -76,10 +411,15 @@ where
         format!("Accept: application/json"),
     ];
 
-    get_json(sess, &format!("{}{}", AZURE_API_BASE, url), &headers)
+    let url = format!("{}{}", base, url);
+    let response = get_json_response(sess, &url, &headers, &AZURE_RETRY.with_token(token));
+    Box::new(response.map(|(list, response)| {
+        let continuation = response.header("x-ms-continuationtoken");
+        (list, continuation)
+    }))
 }
 
-pub fn azure_patch(sess: &Session, url: &str, token: &str, body: &str) -> MyFuture<()> {
+pub fn azure_patch(sess: &Session, base: &str, url: &str, token: &str, body: &str) -> MyFuture<()> {
     let base64 = base64::encode(&format!(":{}", token));
     let headers = vec![
         format!("Authorization: Basic {}", base64),
@@ -87,118 +427,457 @@ pub fn azure_patch(sess: &Session, url: &str, token: &str, body: &str) -> MyFutu
         format!("Content-Type: application/json"),
     ];
 
-    let response = patch(sess, &format!("{}{}", AZURE_API_BASE, url), &headers, body);
+    let response = patch(
+        sess,
+        &format!("{}{}", base, url),
+        &headers,
+        body,
+        &AZURE_RETRY.with_token(token),
+    );
     Box::new(response.map(|_| ()))
 }
 
-pub fn get_json<T>(sess: &Session, url: &str, headers: &[String]) -> MyFuture<T>
+pub fn github_get<T>(sess: &Session, url: &str, token: &str) -> MyFuture<T>
 where
     T: Decodable + 'static,
 {
-    let response = get(sess, url, headers);
-    let ret = response.and_then(|response| {
-        let body = response.body.lock().unwrap();
-        let json = try!(str::from_utf8(&body));
-        let ret = try!(json::decode(json).chain_err(|| { format!("failed to decode: {}", json) }));
-        Ok(ret)
-    });
-    Box::new(ret)
+    let headers = vec![
+        format!("Authorization: token {}", token),
+        format!("Accept: application/vnd.github.v3+json"),
+    ];
+
+    get_json(
+        sess,
+        &format!("{}{}", GITHUB_API_BASE, url),
+        &headers,
+        &GITHUB_RETRY.with_token(token),
+    )
 }
 
-pub fn get(sess: &Session, url: &str, headers: &[String]) -> MyFuture<Response> {
-    let mut handle = Easy::new();
-    let mut list = List::new();
-    t!(list.append(AGENT));
-    for header in headers {
-        t!(list.append(header));
-    }
+pub fn github_post(sess: &Session, url: &str, token: &str) -> MyFuture<()> {
+    let headers = vec![
+        format!("Authorization: token {}", token),
+        format!("Accept: application/vnd.github.v3+json"),
+    ];
+
+    let response = post(
+        sess,
+        &format!("{}{}", GITHUB_API_BASE, url),
+        &headers,
+        &GITHUB_RETRY.with_token(token),
+    );
+    Box::new(response.map(|_| ()))
+}
+
+pub fn gitlab_get<T>(sess: &Session, base: &str, url: &str, token: &str) -> MyFuture<T>
+where
+    T: Decodable + 'static,
+{
+    let headers = vec![
+        format!("PRIVATE-TOKEN: {}", token),
+        format!("Accept: application/json"),
+    ];
 
-    t!(handle.http_headers(list));
-    t!(handle.get(true));
-    t!(handle.url(url));
+    get_json(
+        sess,
+        &format!("{}/api/v4{}", base, url),
+        &headers,
+        &GITLAB_RETRY.with_token(token),
+    )
+}
 
-    perform(sess, handle, url)
+pub fn gitlab_post(sess: &Session, base: &str, url: &str, token: &str) -> MyFuture<()> {
+    let headers = vec![
+        format!("PRIVATE-TOKEN: {}", token),
+        format!("Accept: application/json"),
+    ];
+
+    let response = post(
+        sess,
+        &format!("{}/api/v4{}", base, url),
+        &headers,
+        &GITLAB_RETRY.with_token(token),
+    );
+    Box::new(response.map(|_| ()))
 }
 
-pub fn delete(sess: &Session, url: &str, headers: &[String]) -> MyFuture<Response> {
-    let mut handle = Easy::new();
-    let mut list = List::new();
-    t!(list.append(AGENT));
-    for header in headers {
-        t!(list.append(header));
-    }
+pub fn circleci_get<T>(sess: &Session, base: &str, url: &str, token: &str) -> MyFuture<T>
+where
+    T: Decodable + 'static,
+{
+    let headers = vec![
+        format!("Circle-Token: {}", token),
+        format!("Accept: application/json"),
+    ];
 
-    t!(handle.http_headers(list));
-    t!(handle.custom_request("DELETE"));
-    t!(handle.url(url));
+    get_json(
+        sess,
+        &format!("{}{}", base, url),
+        &headers,
+        &CIRCLECI_RETRY.with_token(token),
+    )
+}
 
-    perform(sess, handle, url)
+pub fn circleci_post(sess: &Session, base: &str, url: &str, token: &str) -> MyFuture<()> {
+    let headers = vec![
+        format!("Circle-Token: {}", token),
+        format!("Accept: application/json"),
+    ];
+
+    let response = post(
+        sess,
+        &format!("{}{}", base, url),
+        &headers,
+        &CIRCLECI_RETRY.with_token(token),
+    );
+    Box::new(response.map(|_| ()))
 }
 
-pub fn post(sess: &Session, url: &str, headers: &[String]) -> MyFuture<Response> {
-    let mut handle = Easy::new();
-    let mut list = List::new();
-    t!(list.append(AGENT));
-    for header in headers {
-        t!(list.append(header));
-    }
+pub fn buildkite_get<T>(sess: &Session, base: &str, url: &str, token: &str) -> MyFuture<T>
+where
+    T: Decodable + 'static,
+{
+    let headers = vec![
+        format!("Authorization: Bearer {}", token),
+        format!("Accept: application/json"),
+    ];
 
-    t!(handle.http_headers(list));
-    t!(handle.post(true));
-    t!(handle.url(url));
+    get_json(
+        sess,
+        &format!("{}{}", base, url),
+        &headers,
+        &BUILDKITE_RETRY.with_token(token),
+    )
+}
 
-    perform(sess, handle, url)
+pub fn buildkite_put(sess: &Session, base: &str, url: &str, token: &str) -> MyFuture<()> {
+    let headers = vec![
+        format!("Authorization: Bearer {}", token),
+        format!("Accept: application/json"),
+    ];
+
+    let response = put(
+        sess,
+        &format!("{}{}", base, url),
+        &headers,
+        &BUILDKITE_RETRY.with_token(token),
+    );
+    Box::new(response.map(|_| ()))
+}
+
+pub fn get_json<T>(sess: &Session, url: &str, headers: &[String], retry: &Retry) -> MyFuture<T>
+where
+    T: Decodable + 'static,
+{
+    Box::new(get_json_response(sess, url, headers, retry).map(|(ret, _response)| ret))
+}
+
+/// Like `get_json`, but also hands back the response for a look at its
+/// headers.
+pub fn get_json_response<T>(
+    sess: &Session,
+    url: &str,
+    headers: &[String],
+    retry: &Retry,
+) -> MyFuture<(T, Response)>
+where
+    T: Decodable + 'static,
+{
+    let response = get(sess, url, headers, retry);
+    let (metrics, provider) = (sess.metrics.clone(), retry.provider);
+    let url = url.to_string();
+    let ret = response.and_then(move |response| {
+        let ret = {
+            let body = response.body.lock().unwrap();
+            let decode = || {
+                let body = redact::excerpt(&String::from_utf8_lossy(&body));
+                BorsErrorKind::Decode(provider, url.clone(), body)
+            };
+            let decoded = str::from_utf8(&body)
+                .chain_err(&decode)
+                .and_then(|json| json::decode(json).chain_err(&decode));
+            if decoded.is_err() {
+                let labels = [("provider", provider), ("kind", "decode")];
+                metrics.inc("cancelbot_http_errors_total", &labels);
+            }
+            try!(decoded)
+        };
+        Ok((ret, response))
+    });
+    Box::new(ret)
+}
+
+pub fn get(sess: &Session, url: &str, headers: &[String], retry: &Retry) -> MyFuture<Response> {
+    let (url2, headers) = (url.to_string(), headers.to_vec());
+    perform(sess, url, retry, true, move || {
+        let mut handle = try!(easy(&headers));
+        try!(handle.get(true));
+        try!(handle.url(&url2));
+        Ok(handle)
+    })
+}
+
+pub fn delete(sess: &Session, url: &str, headers: &[String], retry: &Retry) -> MyFuture<Response> {
+    let (url2, headers) = (url.to_string(), headers.to_vec());
+    perform(sess, url, retry, true, move || {
+        let mut handle = try!(easy(&headers));
+        try!(handle.custom_request("DELETE"));
+        try!(handle.url(&url2));
+        Ok(handle)
+    })
 }
 
-pub fn patch(sess: &Session, url: &str, headers: &[String], body: &str) -> MyFuture<Response> {
+/// POSTs aren't idempotent, so are only retried when they can't have done
+/// anything the first time around.
+pub fn post(sess: &Session, url: &str, headers: &[String], retry: &Retry) -> MyFuture<Response> {
+    let (url2, headers) = (url.to_string(), headers.to_vec());
+    perform(sess, url, retry, false, move || {
+        let mut handle = try!(easy(&headers));
+        try!(handle.post(true));
+        try!(handle.url(&url2));
+        Ok(handle)
+    })
+}
+
+pub fn put(sess: &Session, url: &str, headers: &[String], retry: &Retry) -> MyFuture<Response> {
+    let (url2, headers) = (url.to_string(), headers.to_vec());
+    perform(sess, url, retry, true, move || {
+        let mut handle = try!(easy(&headers));
+        try!(handle.custom_request("PUT"));
+        try!(handle.url(&url2));
+        Ok(handle)
+    })
+}
+
+pub fn patch(
+    sess: &Session,
+    url: &str,
+    headers: &[String],
+    body: &str,
+    retry: &Retry,
+) -> MyFuture<Response> {
+    let (url2, headers, body) = (url.to_string(), headers.to_vec(), body.to_string());
+    perform(sess, url, retry, true, move || {
+        let mut handle = try!(easy(&headers));
+        try!(handle.post_fields_copy(body.as_bytes()));
+        try!(handle.custom_request("PATCH"));
+        try!(handle.url(&url2));
+        Ok(handle)
+    })
+}
+
+fn easy(headers: &[String]) -> Result<Easy, curl::Error> {
     let mut handle = Easy::new();
     let mut list = List::new();
-    t!(list.append(AGENT));
+    try!(list.append(AGENT));
     for header in headers {
-        t!(list.append(header));
+        try!(list.append(header));
     }
+    try!(handle.http_headers(list));
+    Ok(handle)
+}
+
+/// Performs the request built by `request`, retrying it according to `retry`
+/// if it fails in a way that might go away. Requests which aren't
+/// `idempotent` are only retried if the server can't have acted on them.
+pub fn perform<F>(
+    sess: &Session,
+    url: &str,
+    retry: &Retry,
+    idempotent: bool,
+    request: F,
+) -> MyFuture<Response>
+where
+    F: Fn() -> Result<Easy, curl::Error> + 'static,
+{
+    let sess = sess.clone();
+    let url = url.to_string();
+    let retry = retry.clone();
+    let response = future::loop_fn(1, move |attempt| {
+        let handle = sess.handle.clone();
+        let url = url.clone();
+        let retry = retry.clone();
+        let easy = match request().chain_err(|| BorsErrorKind::Request(retry.provider, url.clone()))
+        {
+            Ok(easy) => easy,
+            Err(e) => return Box::new(futures::failed(e)) as MyFuture<_>,
+        };
+        let attempt = attempt_once(&sess, &retry, easy, &url).then(move |result| {
+            let failure = match result {
+                Ok(response) => {
+                    return Box::new(futures::finished(Loop::Break(response))) as MyFuture<_>
+                }
+                Err(failure) => failure,
+            };
+            let retryable = match failure.retry {
+                Retryable::Always => true,
+                Retryable::IfIdempotent => idempotent,
+                Retryable::Never => false,
+            };
+            if !retryable || attempt >= retry.attempts {
+                return Box::new(futures::failed(failure.error));
+            }
+            let delay = failure.wait.unwrap_or_else(|| retry.backoff(attempt));
+            if delay > MAX_RETRY_WAIT {
+                let kind = BorsErrorKind::RetryTooLate(retry.provider, delay.as_secs());
+                return Box::new(futures::done(Err(failure.error).chain_err(|| kind)));
+            }
+            warn!(
+                { provider: retry.provider, error: log::chain(&failure.error) },
+                "retrying {} in {}ms (attempt {} of {})",
+                url,
+                delay.as_secs() * 1000 + delay.subsec_nanos() as u64 / 1_000_000,
+                attempt + 1,
+                retry.attempts
+            );
+            let timeout = match Timeout::new(delay, &handle) {
+                Ok(timeout) => timeout,
+                Err(e) => return Box::new(futures::failed(e.into())),
+            };
+            Box::new(
+                timeout
+                    .map(move |()| Loop::Continue(attempt + 1))
+                    .map_err(From::from),
+            )
+        });
+        Box::new(attempt)
+    });
+    Box::new(response)
+}
 
-    t!(handle.http_headers(list));
-    t!(handle.post_fields_copy(body.as_bytes()));
-    t!(handle.custom_request("PATCH"));
-    t!(handle.url(url));
+/// Why a single attempt at a request failed, and whether to try again.
+struct Failure {
+    error: BorsError,
+    retry: Retryable,
+    /// How long the server asked us to wait before trying again.
+    wait: Option<Duration>,
+}
 
-    perform(sess, handle, url)
+enum Retryable {
+    Never,
+    /// The server can't have acted on the request.
+    Always,
+    /// The server may or may not have acted on the request.
+    IfIdempotent,
 }
 
-pub fn perform(sess: &Session, mut easy: Easy, url: &str) -> MyFuture<Response> {
-    println!("fetching: {}", url);
+fn attempt_once(
+    sess: &Session,
+    retry: &Retry,
+    mut easy: Easy,
+    url: &str,
+) -> Box<Future<Item = Response, Error = Failure>> {
+    let provider = retry.provider;
+    debug!({ provider: provider }, "fetching: {}", url);
     let headers = Arc::new(Mutex::new(Vec::new()));
     let data = Arc::new(Mutex::new(Vec::new()));
 
     let (data2, headers2) = (data.clone(), headers.clone());
-    t!(easy.header_function(move |data| {
-        headers2.lock().unwrap().push(data.to_owned());
-        true
-    }));
-    t!(easy.write_function(move |buf| {
-        data2.lock().unwrap().extend_from_slice(&buf);
-        Ok(buf.len())
-    }));
-
-    let response = sess.perform(easy);
+    let setup = easy
+        .header_function(move |data| {
+            headers2.lock().unwrap().push(data.to_owned());
+            true
+        })
+        .and_then(|()| {
+            easy.write_function(move |buf| {
+                data2.lock().unwrap().extend_from_slice(&buf);
+                Ok(buf.len())
+            })
+        });
+    if let Err(e) = setup.chain_err(|| BorsErrorKind::Request(provider, url.to_string())) {
+        return Box::new(futures::failed(Failure {
+            error: e,
+            retry: Retryable::Never,
+            wait: None,
+        }));
+    }
+
+    let response = sess.curl.perform(easy);
     let url = url.to_string();
-    let checked_response = response.map_err(|e| e.into()).and_then(move |mut easy| {
-        println!("finished: {}", url);
-        match t!(easy.response_code()) {
-            200 | 204 => Ok(Response {
+    let sess = sess.clone();
+    let retry = retry.clone();
+    let metrics = sess.metrics.clone();
+    let start = time::precise_time_ns();
+    let elapsed = move || (time::precise_time_ns() - start) as f64 / 1e9;
+    let labels = [("provider", provider)];
+    let checked_response = response
+        .map_err(move |e| {
+            metrics.observe(
+                "cancelbot_http_request_duration_seconds",
+                &labels,
+                elapsed(),
+            );
+            let labels = [("provider", provider), ("kind", "transport")];
+            metrics.inc("cancelbot_http_errors_total", &labels);
+            let error = e.into_error();
+            let retry = match error
+                .get_ref()
+                .and_then(|e| e.downcast_ref::<curl::Error>())
+            {
+                Some(e) if e.is_couldnt_resolve_host() || e.is_couldnt_connect() => {
+                    Retryable::Always
+                }
+                Some(e)
+                    if e.is_operation_timedout()
+                        || e.is_got_nothing()
+                        || e.is_send_error()
+                        || e.is_recv_error() =>
+                {
+                    Retryable::IfIdempotent
+                }
+                _ => Retryable::Never,
+            };
+            Failure {
+                error: error.into(),
+                retry: retry,
+                wait: None,
+            }
+        })
+        .and_then(move |mut easy| {
+            debug!({ provider: provider }, "finished: {}", url);
+            let metrics = &sess.metrics;
+            metrics.observe(
+                "cancelbot_http_request_duration_seconds",
+                &labels,
+                elapsed(),
+            );
+            let code = match easy.response_code() {
+                Ok(code) => code,
+                Err(e) => {
+                    return Err(Failure {
+                        error: e.into(),
+                        retry: Retryable::Never,
+                        wait: None,
+                    })
+                }
+            };
+            let response = Response {
                 easy: easy,
                 headers: headers,
                 body: data,
-            }),
-            code => Err(format!(
-                "not a 200 code: {}\n\n{}\n",
-                code,
-                String::from_utf8_lossy(&data.lock().unwrap())
-            )
-            .into()),
-        }
-    });
+            };
+            sess.record_budget(&retry, code, &response);
+            if let 200 | 202 | 204 = code {
+                return Ok(response);
+            }
+            let labels = [("provider", provider), ("kind", "status")];
+            metrics.inc("cancelbot_http_errors_total", &labels);
+            let retryable = match code {
+                429 => Retryable::Always,
+                // GitHub reports exhausted rate limits as 403s
+                403 if response.header("X-RateLimit-Remaining") == Some("0".to_string()) => {
+                    Retryable::Always
+                }
+                500 | 502 | 503 | 504 => Retryable::IfIdempotent,
+                _ => Retryable::Never,
+            };
+            let body = redact::excerpt(&String::from_utf8_lossy(&response.body.lock().unwrap()));
+            Err(Failure {
+                error: BorsErrorKind::Status(provider, url, code, body).into(),
+                retry: retryable,
+                wait: response.retry_after(retry.reset),
+            })
+        });
 
     Box::new(checked_response)
 }
@@ -0,0 +1,128 @@
+//! Structured log events, written to stdout either for humans or as one JSON
+//! object per line.
+//!
+//! Events are emitted through the `debug!`, `info!`, `warn!` and `error!`
+//! macros, which take the fields of the event ahead of the message:
+//!
+//! ```ignore
+//! info!({ provider: name, repo: repo.full_name() }, "cancelling build {}", 4);
+//! ```
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use rustc_serialize::json;
+use time;
+
+use errors::*;
+use redact::redact;
+
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum Level {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Human,
+    Json,
+}
+
+static LEVEL: AtomicUsize = AtomicUsize::new(Level::Info as usize);
+static FORMAT: AtomicUsize = AtomicUsize::new(Format::Human as usize);
+
+/// Sets the least severe level that's still logged, and how.
+pub fn init(level: Level, format: Format) {
+    LEVEL.store(level as usize, Ordering::SeqCst);
+    FORMAT.store(format as usize, Ordering::SeqCst);
+}
+
+impl Level {
+    pub fn parse(s: &str) -> Option<Level> {
+        match s {
+            "debug" => Some(Level::Debug),
+            "info" => Some(Level::Info),
+            "warn" => Some(Level::Warn),
+            "error" => Some(Level::Error),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match *self {
+            Level::Debug => "debug",
+            Level::Info => "info",
+            Level::Warn => "warn",
+            Level::Error => "error",
+        }
+    }
+}
+
+impl Format {
+    pub fn parse(s: &str) -> Option<Format> {
+        match s {
+            "human" => Some(Format::Human),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as usize >= LEVEL.load(Ordering::SeqCst)
+}
+
+/// Writes out an event, if its level is enabled. Use the macros instead.
+pub fn emit(level: Level, message: String, fields: Vec<(&'static str, String)>) {
+    if !enabled(level) {
+        return;
+    }
+    // whatever's logged may have come from a CI service or an error, so
+    // could contain tokens
+    let message = redact(&message);
+    let fields = fields
+        .into_iter()
+        .map(|(key, value)| (key, redact(&value)))
+        .collect::<Vec<_>>();
+    let timestamp = time::now_utc().rfc3339().to_string();
+    let line = if FORMAT.load(Ordering::SeqCst) == Format::Json as usize {
+        let mut line = format!(
+            "{{\"timestamp\":{},\"level\":{},\"message\":{}",
+            encode(&timestamp),
+            encode(level.name()),
+            encode(&message)
+        );
+        for (key, value) in fields {
+            let _ = write!(line, ",{}:{}", encode(key), encode(&value));
+        }
+        line.push('}');
+        line
+    } else {
+        let mut line = format!("{} {:5} {}", timestamp, level.name(), message);
+        for (key, value) in fields {
+            if value.contains(char::is_whitespace) || value.is_empty() {
+                let _ = write!(line, " {}={:?}", key, value);
+            } else {
+                let _ = write!(line, " {}={}", key, value);
+            }
+        }
+        line
+    };
+    println!("{}", line);
+}
+
+/// Every message in the chain of `error`, outermost first.
+pub fn chain(error: &BorsError) -> String {
+    error
+        .iter()
+        .map(|e| e.to_string())
+        .collect::<Vec<_>>()
+        .join(": ")
+}
+
+fn encode(s: &str) -> String {
+    json::encode(&s).unwrap()
+}
@@ -1,68 +1,291 @@
 extern crate curl;
 extern crate futures;
 extern crate getopts;
+extern crate glob;
+extern crate hex;
+extern crate hmac;
+extern crate hyper;
+extern crate openssl;
 extern crate rustc_serialize;
+extern crate sha2;
 extern crate time;
 extern crate tokio_core;
 extern crate tokio_curl;
+extern crate toml;
 #[macro_use]
 extern crate error_chain;
 
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::env;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use std::time::Duration;
 
 use errors::*;
 use futures::Future;
 use getopts::Options;
+use glob::Pattern;
+use policy::Action;
+use provider::CiProvider;
 use tokio_core::reactor::{Core, Handle, Timeout};
-use tokio_curl::Session;
 
-macro_rules! t {
-    ($e:expr) => {
-        match $e {
-            Ok(e) => e,
-            Err(e) => panic!("{} failed with {}", stringify!($e), e),
+/// Logs an event at `$level` if it's enabled, optionally with fields given
+/// ahead of the message, e.g. `log!(Info, { repo: name }, "cancelling")`.
+macro_rules! log {
+    ($level:ident, { $($key:ident: $value:expr),* $(,)* }, $($arg:tt)+) => {
+        if ::log::enabled(::log::Level::$level) {
+            ::log::emit(
+                ::log::Level::$level,
+                format!($($arg)+),
+                vec![$((stringify!($key), $value.to_string())),*],
+            );
         }
     };
+    ($level:ident, $($arg:tt)+) => {
+        log!($level, {}, $($arg)+)
+    };
+}
+
+macro_rules! debug {
+    ($($arg:tt)+) => { log!(Debug, $($arg)+) };
+}
+
+macro_rules! info {
+    ($($arg:tt)+) => { log!(Info, $($arg)+) };
+}
+
+macro_rules! warn {
+    ($($arg:tt)+) => { log!(Warn, $($arg)+) };
 }
 
+macro_rules! error {
+    ($($arg:tt)+) => { log!(Error, $($arg)+) };
+}
+
+/// How long a check gets before whatever it's still waiting for is given up
+/// on.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(30);
+
 type MyFuture<T> = Box<Future<Item = T, Error = BorsError>>;
 
 #[derive(Clone)]
 struct State {
-    travis_token: String,
-    appveyor_token: String,
-    session: Session,
     repos: Vec<Repo>,
-    branch: String,
-    appveyor_account_name: String,
-    azure_pipelines_token: String,
+    providers: Vec<Rc<CiProvider>>,
+    heads: Rc<heads::Heads>,
+    /// Shared by all providers, keeping track of their rate limits.
+    session: http::Session,
+    metrics: Rc<metrics::Metrics>,
+    /// Estimate of how long a cancelled build would have kept running.
+    build_minutes: f64,
+    /// Only log what would be cancelled, recording it in `would_cancel`.
+    dry_run: bool,
+    would_cancel: Rc<RefCell<Vec<String>>>,
+    audit: Option<Rc<audit::AuditLog>>,
+    /// Root of the Travis API, which also hands out the key its webhook
+    /// notifications are signed with.
+    travis_url: String,
 }
 
 #[derive(Clone)]
 struct Repo {
     user: String,
     name: String,
+    /// Branches whose builds are checked.
+    branches: Vec<String>,
+    /// Names of the providers this repository builds on, or `None` for all of
+    /// them.
+    providers: Option<Vec<String>>,
+    travis: Option<config::Travis>,
+    appveyor: Option<config::AppVeyor>,
+    azure: Option<config::Azure>,
+    buildkite: Option<buildkite::Pipeline>,
+    circleci: Option<config::CircleCi>,
+    /// Local clone to resolve branch heads in instead of asking GitHub.
+    checkout: Option<PathBuf>,
+    /// Whether the repository is hosted on GitHub, and so has the heads of
+    /// its branches there.
+    github_heads: bool,
 }
 
 mod appveyor;
+mod audit;
 mod azure;
+mod buildkite;
+mod circleci;
+mod config;
 mod errors;
+mod github;
+mod gitlab;
+mod heads;
 mod http;
+mod log;
+mod metrics;
+mod policy;
+mod provider;
+mod redact;
+mod serve;
 mod travis;
 
 fn main() {
     let args = env::args().skip(1).collect::<Vec<_>>();
     let mut opts = Options::new();
-    opts.reqopt("t", "travis", "travis token", "TOKEN");
-    opts.reqopt("a", "appveyor", "appveyor token", "TOKEN");
-    opts.reqopt("b", "branch", "branch to work with", "BRANCH");
+    opts.optopt("t", "travis", "travis token", "TOKEN");
+    opts.optopt("a", "appveyor", "appveyor token", "TOKEN");
+    opts.optmulti(
+        "b",
+        "branch",
+        "branch, or glob pattern of branches, to work with",
+        "BRANCH",
+    );
+    opts.optopt("c", "config", "file with per-repository settings", "FILE");
     opts.optopt("", "appveyor-account", "appveyor account name", "ACCOUNT");
     opts.optopt("", "azure-pipelines-token", "", "TOKEN");
+    opts.optopt("", "github-token", "github actions token", "TOKEN");
+    opts.optopt("", "gitlab-token", "gitlab token", "TOKEN");
+    opts.optopt("", "circleci-token", "circleci token", "TOKEN");
+    opts.optopt("", "buildkite-token", "buildkite token", "TOKEN");
+    opts.optmulti(
+        "",
+        "buildkite-pipeline",
+        "buildkite pipeline of a repository",
+        "USER/NAME=ORG/PIPELINE",
+    );
+    opts.optopt(
+        "",
+        "travis-url",
+        &format!(
+            "travis api to talk to, or `org`/`com` (default {})",
+            http::TRAVIS_URL
+        ),
+        "URL",
+    );
+    opts.optopt(
+        "",
+        "appveyor-url",
+        &format!(
+            "appveyor instance to talk to (default {})",
+            http::APPVEYOR_URL
+        ),
+        "URL",
+    );
+    opts.optopt(
+        "",
+        "azure-url",
+        &format!(
+            "azure devops instance to talk to (default {})",
+            http::AZURE_URL
+        ),
+        "URL",
+    );
+    opts.optopt(
+        "",
+        "buildkite-url",
+        &format!("buildkite api to talk to (default {})", http::BUILDKITE_URL),
+        "URL",
+    );
+    opts.optopt(
+        "",
+        "circleci-url",
+        &format!("circleci api to talk to (default {})", http::CIRCLECI_URL),
+        "URL",
+    );
+    opts.optopt(
+        "",
+        "gitlab-url",
+        "gitlab instance to talk to (default https://gitlab.com)",
+        "URL",
+    );
+    opts.optopt(
+        "",
+        "max-pages",
+        "most pages of travis/azure build history to look through (default 5)",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "interval",
+        "keep running, checking builds this often (e.g. 60s, 5m)",
+        "DURATION",
+    );
+    opts.optopt(
+        "",
+        "jitter",
+        "wait up to this much longer between checks",
+        "DURATION",
+    );
+    opts.optflag("", "dry-run", "only report which builds would be cancelled");
+    opts.optflag(
+        "",
+        "github-heads",
+        "look up branch heads on GitHub, for repositories hosted there",
+    );
+    opts.optopt(
+        "",
+        "build-minutes",
+        "CI minutes each cancellation is estimated to save, for /metrics (default 30)",
+        "MINUTES",
+    );
+    opts.optopt(
+        "",
+        "audit-log",
+        "file to record every cancellation in, and to read back in `audit` mode",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "repo",
+        "only show cancellations in this repository",
+        "USER/NAME",
+    );
+    opts.optopt("", "build", "only show cancellations of this build", "ID");
+    opts.optopt(
+        "",
+        "since",
+        "only show cancellations since then (e.g. 2019-05-01, 2h)",
+        "TIME",
+    );
+    opts.optopt("", "until", "only show cancellations before then", "TIME");
+    opts.optopt(
+        "",
+        "max-body-excerpt",
+        "most bytes of a response body to show in errors (default 512)",
+        "BYTES",
+    );
+    opts.optopt(
+        "",
+        "log-level",
+        "least severe events to log: debug, info, warn or error (default info)",
+        "LEVEL",
+    );
+    opts.optopt(
+        "",
+        "log-format",
+        "log as `human` readable lines or one `json` object per line",
+        "FORMAT",
+    );
+    opts.optopt(
+        "",
+        "listen",
+        "address to serve webhooks and /metrics on (default 127.0.0.1:8080 in `serve` mode)",
+        "ADDR",
+    );
+    opts.optopt(
+        "",
+        "webhook-secret",
+        "secret webhooks are signed with in `serve` mode",
+        "SECRET",
+    );
 
     let usage = || -> ! {
-        println!("{}", opts.usage("usage: ./foo -a ... -t ..."));
+        println!(
+            "{}",
+            opts.usage(
+                "usage: ./foo [serve] [-c FILE] -b ... -a ... -t ... USER/NAME...\n       \
+                 ./foo audit [-c FILE] [--audit-log FILE] [--repo ...] [--since ...]",
+            )
+        );
         std::process::exit(1);
     };
 
@@ -74,69 +297,563 @@ fn main() {
         }
     };
 
-    let mut core = t!(Core::new());
+    let level = match matches.opt_str("log-level") {
+        Some(s) => match log::Level::parse(&s) {
+            Some(level) => level,
+            None => {
+                println!("error: invalid --log-level: {}", s);
+                usage();
+            }
+        },
+        None => log::Level::Info,
+    };
+    let format = match matches.opt_str("log-format") {
+        Some(s) => match log::Format::parse(&s) {
+            Some(format) => format,
+            None => {
+                println!("error: invalid --log-format: {}", s);
+                usage();
+            }
+        },
+        None => log::Format::Human,
+    };
+    log::init(level, format);
+
+    let duration = |name: &str| match matches.opt_str(name) {
+        Some(s) => match parse_duration(&s) {
+            Some(d) => Some(d),
+            None => {
+                println!("error: invalid duration for --{}: {}", name, s);
+                usage();
+            }
+        },
+        None => None,
+    };
+    let interval = duration("interval");
+    // checking back to back would only hammer the CI services
+    if interval == Some(Duration::new(0, 0)) {
+        println!("error: --interval must be longer than 0s");
+        usage();
+    }
+    let jitter = duration("jitter").unwrap_or(Duration::new(0, 0));
+
+    let mut core = match Core::new() {
+        Ok(core) => core,
+        Err(e) => {
+            println!("error: failed to start the event loop: {}", e);
+            std::process::exit(1);
+        }
+    };
     let handle = core.handle();
 
-    let state = State {
-        travis_token: matches.opt_str("t").unwrap(),
-        appveyor_token: matches.opt_str("a").unwrap(),
-        repos: matches
-            .free
-            .iter()
-            .map(|m| {
-                let mut parts = m.splitn(2, '/');
-                Repo {
-                    user: parts.next().unwrap().to_string(),
-                    name: parts.next().unwrap().to_string(),
+    let config = match matches.opt_str("config") {
+        Some(path) => match config::load(Path::new(&path)) {
+            Ok(config) => config,
+            Err(e) => {
+                println!("error: {}", e);
+                std::process::exit(1);
+            }
+        },
+        None => config::Config::default(),
+    };
+    let tokens = config.tokens.unwrap_or_default();
+    let audit_log = matches
+        .opt_str("audit-log")
+        .or(config.audit_log)
+        .map(|path| Rc::new(audit::AuditLog::new(Path::new(&path))));
+
+    // `audit` only reads back what's been cancelled
+    if matches.free.first().map(|s| &s[..]) == Some("audit") {
+        let audit_log = match audit_log {
+            Some(audit_log) => audit_log,
+            None => {
+                println!("error: --audit-log is required to query it");
+                usage();
+            }
+        };
+        let time = |name: &str| {
+            matches
+                .opt_str(name)
+                .map(|s| match audit::parse_time(&s, parse_duration) {
+                    Some(time) => time,
+                    None => {
+                        println!("error: invalid time for --{}: {}", name, s);
+                        usage();
+                    }
+                })
+        };
+        let query = audit::Query {
+            repo: matches.opt_str("repo"),
+            build: matches.opt_str("build"),
+            since: time("since"),
+            until: time("until"),
+        };
+        match audit_log.entries() {
+            Ok(entries) => {
+                for entry in entries.iter().filter(|e| query.matches(e)) {
+                    println!("{}", entry.describe());
                 }
-            })
-            .collect(),
-        session: Session::new(handle.clone()),
-        branch: matches.opt_str("b").unwrap(),
-        appveyor_account_name: matches.opt_str("appveyor-account").unwrap(),
-        azure_pipelines_token: matches.opt_str("azure-pipelines-token").unwrap(),
+            }
+            Err(e) => {
+                println!("error: {}", redact::redact(&log::chain(&e)));
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let max_pages = match matches.opt_str("max-pages") {
+        Some(s) => match s.parse() {
+            Ok(n) if n > 0 => n,
+            _ => {
+                println!("error: invalid number for --max-pages: {}", s);
+                usage();
+            }
+        },
+        None => config.max_pages.unwrap_or(5),
     };
 
-    core.run(state.check(&handle)).unwrap();
-}
+    let resolve = |reference: &str| match config::token(reference) {
+        Ok(token) => {
+            redact::add_secret(&token);
+            token
+        }
+        Err(e) => {
+            println!("error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    // tokens on the command line take precedence over the config file
+    let token = |flag: &str, configured: &Option<String>| match matches.opt_str(flag) {
+        Some(token) => {
+            redact::add_secret(&token);
+            Some(token)
+        }
+        None => configured.as_ref().map(|reference| resolve(reference)),
+    };
 
-impl State {
-    fn check(&self, handle: &Handle) -> MyFuture<()> {
-        println!(
-            "--------------------------------------------------------\n\
-             {} - starting check",
-            time::now().rfc822z()
-        );
-        let travis = self.check_travis();
-        let travis = travis.then(|result| {
-            println!("travis result {:?}", result);
-            Ok(())
+    match matches.opt_str("max-body-excerpt") {
+        Some(s) => match s.parse() {
+            Ok(n) => redact::set_max_excerpt(n),
+            Err(_) => {
+                println!("error: invalid number for --max-body-excerpt: {}", s);
+                usage();
+            }
+        },
+        None => {
+            if let Some(n) = config.max_body_excerpt {
+                redact::set_max_excerpt(n);
+            }
+        }
+    }
+
+    let build_minutes = match matches.opt_str("build-minutes") {
+        Some(s) => match s.parse() {
+            Ok(n) => n,
+            Err(_) => {
+                println!("error: invalid number for --build-minutes: {}", s);
+                usage();
+            }
+        },
+        None => config.build_minutes.unwrap_or(30.0),
+    };
+
+    let metrics = Rc::new(metrics::Metrics::default());
+    let session = http::Session::new(handle.clone(), metrics.clone());
+    // urls on the command line take precedence over the config file
+    let base_url = |flag: &str, configured: &Option<String>, default: &str| {
+        let url = matches
+            .opt_str(flag)
+            .or_else(|| configured.clone())
+            .unwrap_or_else(|| default.to_string());
+        url.trim_end_matches('/').to_string()
+    };
+    let travis_url = travis::api_url(&base_url(
+        "travis-url",
+        &config.travis_url,
+        http::TRAVIS_URL,
+    ));
+    // the GitHub token doubles as the way to look up branch heads, for the
+    // repositories which opt into that
+    let github_token = token("github-token", &tokens.github);
+
+    // `serve` waits for webhooks instead of checking everything up front
+    let serve = matches.free.first().map(|s| &s[..]) == Some("serve");
+    let names = if serve {
+        &matches.free[1..]
+    } else {
+        &matches.free[..]
+    };
+
+    let branches = matches.opt_strs("b");
+    let github_heads = matches.opt_present("github-heads") || config.github_heads == Some(true);
+    let mut repos = names
+        .iter()
+        .map(|m| match parse_repo(m) {
+            Ok((user, name)) => {
+                let mut repo = Repo::new(user, name, branches.clone());
+                repo.github_heads = github_heads;
+                repo
+            }
+            Err(e) => {
+                println!("error: {}", e);
+                usage();
+            }
+        })
+        .collect::<Vec<_>>();
+
+    for arg in matches.opt_strs("buildkite-pipeline") {
+        let (name, pipeline) = match parse_pipeline(&arg) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                println!("error: {}", e);
+                usage();
+            }
+        };
+        for repo in repos.iter_mut().filter(|r| r.full_name() == name) {
+            repo.buildkite = Some(pipeline.clone());
+        }
+    }
+
+    for configured in config.repo.unwrap_or_default() {
+        let (user, name) = match parse_repo(&configured.name) {
+            Ok(parts) => parts,
+            Err(e) => {
+                println!("error: {}", e);
+                std::process::exit(1);
+            }
+        };
+        let branches = configured.branches.unwrap_or_else(|| branches.clone());
+        let mut repo = Repo::new(user, name, branches);
+        repo.providers = configured.providers;
+        repo.travis = configured.travis.map(|mut travis| {
+            travis.url = travis.url.map(|url| travis::api_url(&url));
+            travis.token = travis.token.map(|reference| resolve(&reference));
+            travis
         });
-        let appveyor = self.check_appveyor();
-        let appveyor = appveyor.then(|result| {
-            println!("appveyor result {:?}", result);
-            Ok(())
+        repo.appveyor = configured.appveyor.map(|mut appveyor| {
+            appveyor.url = appveyor
+                .url
+                .map(|url| url.trim_end_matches('/').to_string());
+            appveyor.token = appveyor.token.map(|reference| resolve(&reference));
+            appveyor
         });
-        let azure_pipelines = self.check_azure_pipelines();
-        let azure_pipelines = azure_pipelines.then(|result| {
-            println!("azure_pipelines result {:?}", result);
-            Ok(())
+        repo.azure = configured.azure_pipelines.map(|mut azure| {
+            azure.url = azure.url.map(|url| url.trim_end_matches('/').to_string());
+            azure.token = azure.token.map(|reference| resolve(&reference));
+            azure
         });
+        repo.buildkite = configured.buildkite;
+        repo.circleci = configured.circleci;
+        repo.checkout = configured.checkout.map(PathBuf::from);
+        repo.github_heads = configured.github_heads.unwrap_or(github_heads);
+        repos.push(repo);
+    }
+
+    for repo in repos.iter() {
+        if repo.branches.is_empty() {
+            println!("error: no branch to check for {}", repo.full_name());
+            usage();
+        }
+        if repo.github_heads && github_token.is_none() {
+            println!(
+                "error: looking up the branch heads of {} on GitHub needs --github-token",
+                repo.full_name()
+            );
+            usage();
+        }
+        for branch in repo.branches.iter() {
+            if let Err(e) = Pattern::new(branch) {
+                let kind = BorsErrorKind::InvalidPattern(branch.clone(), e.to_string());
+                println!("error: {}", kind);
+                usage();
+            }
+        }
+    }
+
+    // Travis, AppVeyor and Azure only need a global token for repositories
+    // without one of their own, so any token at all enables them
+    let mut providers: Vec<Rc<CiProvider>> = Vec::new();
+    let travis_token = token("travis", &tokens.travis);
+    if travis_token.is_some()
+        || repos
+            .iter()
+            .any(|r| r.travis.as_ref().map_or(false, |c| c.token.is_some()))
+    {
+        providers.push(Rc::new(travis::Travis {
+            session: session.clone(),
+            token: travis_token,
+            base_url: travis_url.clone(),
+            max_pages: max_pages,
+        }));
+    }
+
+    let appveyor_token = token("appveyor", &tokens.appveyor);
+    if appveyor_token.is_some()
+        || repos
+            .iter()
+            .any(|r| r.appveyor.as_ref().map_or(false, |c| c.token.is_some()))
+    {
+        providers.push(Rc::new(appveyor::AppVeyor {
+            session: session.clone(),
+            token: appveyor_token,
+            base_url: base_url("appveyor-url", &config.appveyor_url, http::APPVEYOR_URL),
+            account_name: matches
+                .opt_str("appveyor-account")
+                .or(config.appveyor_account),
+        }));
+    }
+
+    let azure_token = token("azure-pipelines-token", &tokens.azure_pipelines);
+    if azure_token.is_some()
+        || repos
+            .iter()
+            .any(|r| r.azure.as_ref().map_or(false, |c| c.token.is_some()))
+    {
+        providers.push(Rc::new(azure::AzurePipelines {
+            session: session.clone(),
+            token: azure_token,
+            base_url: base_url("azure-url", &config.azure_url, http::AZURE_URL),
+            max_pages: max_pages,
+        }));
+    }
+
+    if let Some(ref token) = github_token {
+        providers.push(Rc::new(github::GitHubActions {
+            session: session.clone(),
+            token: token.clone(),
+        }));
+    }
+
+    if let Some(token) = token("gitlab-token", &tokens.gitlab) {
+        let base_url = matches
+            .opt_str("gitlab-url")
+            .or(config.gitlab_url)
+            .unwrap_or_else(|| "https://gitlab.com".to_string());
+        providers.push(Rc::new(gitlab::GitLab {
+            session: session.clone(),
+            token: token,
+            base_url: base_url.trim_end_matches('/').to_string(),
+        }));
+    }
+
+    if let Some(token) = token("circleci-token", &tokens.circleci) {
+        providers.push(Rc::new(circleci::CircleCi {
+            session: session.clone(),
+            token: token,
+            base_url: base_url("circleci-url", &config.circleci_url, http::CIRCLECI_URL),
+        }));
+    }
+
+    if let Some(token) = token("buildkite-token", &tokens.buildkite) {
+        providers.push(Rc::new(buildkite::Buildkite {
+            session: session.clone(),
+            token: token,
+            base_url: base_url("buildkite-url", &config.buildkite_url, http::BUILDKITE_URL),
+            jobs: Default::default(),
+        }));
+    }
+
+    let state = State {
+        repos: repos,
+        providers: providers,
+        heads: Rc::new(heads::Heads {
+            session: session.clone(),
+            github_token: github_token,
+        }),
+        session: session,
+        metrics: metrics,
+        build_minutes: build_minutes,
+        dry_run: matches.opt_present("dry-run"),
+        would_cancel: Rc::new(RefCell::new(Vec::new())),
+        audit: audit_log,
+        travis_url: travis_url,
+    };
+
+    let listen = match matches.opt_str("listen") {
+        Some(addr) => Some(addr),
+        None if serve => Some("127.0.0.1:8080".to_string()),
+        None => None,
+    };
+    let listen = listen.map(|addr| match addr.parse() {
+        Ok(addr) => addr,
+        Err(e) => {
+            println!("error: invalid --listen address {}: {}", addr, e);
+            usage();
+        }
+    });
+    let secret = matches.opt_str("webhook-secret");
+    if let Some(ref secret) = secret {
+        redact::add_secret(secret);
+    }
+
+    if serve {
+        if secret.is_none() {
+            println!("error: --webhook-secret is required to serve webhooks");
+            usage();
+        }
+        let addr = match listen {
+            Some(addr) => addr,
+            None => usage(),
+        };
+        if let Err(e) = core.run(serve::serve(state, &addr, secret, &handle)) {
+            println!("error: {}", redact::redact(&log::chain(&e)));
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let interval = match interval {
+        Some(interval) => interval,
+        None => {
+            if let Err(e) = core.run(state.check(&handle)) {
+                error!({ error: log::chain(&e) }, "check failed");
+            }
+            state.report_budgets();
+            return state.report_dry_run();
+        }
+    };
+
+    // a long-running poller can also serve /metrics, and webhooks if it
+    // has a secret for them
+    if let Some(addr) = listen {
+        let server = serve::serve(state.clone(), &addr, secret, &handle);
+        handle.spawn(server.map_err(|e| error!({ error: log::chain(&e) }, "server failed")));
+    }
+
+    // Keep the same event loop and curl session around between rounds so
+    // connections can be reused, and don't let one failed round stop us.
+    loop {
+        // a round never takes longer than the interval, so one that hangs
+        // can't hold up the rounds after it
+        let round = state.with_timeout(state.check(&handle), interval, &handle);
+        if let Err(e) = core.run(round) {
+            error!({ error: log::chain(&e) }, "check failed");
+        }
+        state.report_budgets();
+        state.report_dry_run();
+        let delay = interval
+            .checked_add(random_duration(jitter))
+            .unwrap_or(interval);
+        info!("next check in {}s", delay.as_secs());
+        let wait = Timeout::new(delay, &handle).and_then(|timeout| core.run(timeout));
+        if let Err(e) = wait {
+            error!({ error: e }, "failed to wait for the next check");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Parses durations like `90`, `90s`, `5m` or `1h`, where a bare number is a
+/// number of seconds.
+fn parse_duration(s: &str) -> Option<Duration> {
+    let (number, unit) = match s.find(|c: char| !c.is_digit(10)) {
+        Some(i) => (&s[..i], &s[i..]),
+        None => (s, "s"),
+    };
+    let number = match number.parse::<u64>() {
+        Ok(n) => n,
+        Err(_) => return None,
+    };
+    let secs = match unit {
+        "s" => Some(number),
+        "m" => number.checked_mul(60),
+        "h" => number.checked_mul(60 * 60),
+        _ => return None,
+    };
+    secs.map(|secs| Duration::new(secs, 0))
+}
+
+/// Picks a duration between zero and `max`.
+///
+/// This only needs to spread out requests from several instances, so the
+/// clock is a good enough source of randomness.
+fn random_duration(max: Duration) -> Duration {
+    let max = max
+        .as_secs()
+        .saturating_mul(1_000)
+        .saturating_add(u64::from(max.subsec_nanos()) / 1_000_000);
+    if max == 0 {
+        return Duration::new(0, 0);
+    }
+    let millis = time::precise_time_ns() % max;
+    Duration::from_millis(millis)
+}
+
+impl Repo {
+    fn new(user: &str, name: &str, branches: Vec<String>) -> Repo {
+        Repo {
+            user: user.to_string(),
+            name: name.to_string(),
+            branches: branches,
+            providers: None,
+            travis: None,
+            appveyor: None,
+            azure: None,
+            buildkite: None,
+            circleci: None,
+            checkout: None,
+            github_heads: false,
+        }
+    }
+
+    fn full_name(&self) -> String {
+        format!("{}/{}", self.user, self.name)
+    }
+
+    /// Whether `branch` is one of the branches, or matches one of the
+    /// patterns, in `branches`.
+    fn watches(&self, branch: &str) -> bool {
+        self.branches.iter().any(|b| match Pattern::new(b) {
+            Ok(pattern) => pattern.matches(branch),
+            Err(_) => false,
+        })
+    }
+
+    fn uses(&self, provider: &CiProvider) -> bool {
+        let listed = match self.providers {
+            Some(ref names) => names.iter().any(|name| name == provider.name()),
+            None => true,
+        };
+        listed && provider.covers(self)
+    }
+}
+
+impl State {
+    fn check(&self, handle: &Handle) -> MyFuture<()> {
+        info!("starting check");
+        let requests = self
+            .providers
+            .iter()
+            .map(|provider| self.check_provider(provider.clone()))
+            .collect::<Vec<_>>();
+
+        let requests = futures::collect(requests).map(|_| ());
+        self.with_timeout(Box::new(requests), CHECK_TIMEOUT, handle)
+    }
 
-        let requests = travis
-            .join(appveyor)
-            .map(|_| ())
-            .join(azure_pipelines)
-            .map(|_| ());
-        let timeout = t!(Timeout::new(Duration::new(30, 0), handle));
+    /// Gives up on `requests` if they haven't finished within `timeout`,
+    /// which isn't counted as a failure.
+    fn with_timeout(
+        &self,
+        requests: MyFuture<()>,
+        timeout: Duration,
+        handle: &Handle,
+    ) -> MyFuture<()> {
+        let timeout = match Timeout::new(timeout, handle) {
+            Ok(timeout) => timeout,
+            Err(e) => return Box::new(futures::failed(e.into())),
+        };
+        let metrics = self.metrics.clone();
         Box::new(
             requests
                 .map(Ok)
                 .select(timeout.map(Err).map_err(From::from))
-                .then(|res| match res {
+                .then(move |res| match res {
                     Ok((Ok(()), _timeout)) => Ok(()),
                     Ok((Err(_), _requests)) => {
-                        println!("timeout, canceling requests");
+                        warn!("timeout, canceling requests");
+                        metrics.inc("cancelbot_check_timeouts_total", &[]);
                         Ok(())
                     }
                     Err((e, _other)) => Err(e),
@@ -144,231 +861,359 @@ impl State {
         )
     }
 
-    fn check_travis(&self) -> MyFuture<()> {
+    fn check_provider(&self, provider: Rc<CiProvider>) -> MyFuture<()> {
         let futures = self
             .repos
             .iter()
-            .map(|repo| self.check_travis_repo(repo.clone()))
+            .filter(|repo| repo.uses(&*provider))
+            .flat_map(|repo| {
+                repo.branches
+                    .iter()
+                    .map(|branch| self.check_isolated(provider.clone(), repo.clone(), branch))
+                    .collect::<Vec<_>>()
+            })
             .collect::<Vec<_>>();
         Box::new(futures::collect(futures).map(|_| ()))
     }
 
-    fn check_travis_repo(&self, repo: Repo) -> MyFuture<()> {
-        let url = format!("/repos/{}/{}/builds", repo.user, repo.name);
-        let history = http::travis_get(&self.session, &url, &self.travis_token);
+    /// Checks a single branch of a single repository on every provider, as
+    /// requested by a webhook.
+    fn check_branch(&self, repo: &Repo, branch: &str, handle: &Handle) -> MyFuture<()> {
+        let requests = self
+            .providers
+            .iter()
+            .filter(|&provider| repo.uses(&**provider))
+            .map(|provider| self.check_isolated(provider.clone(), repo.clone(), branch))
+            .collect::<Vec<_>>();
+        let requests = futures::collect(requests).map(|_| ());
+        self.with_timeout(Box::new(requests), CHECK_TIMEOUT, handle)
+    }
 
-        let me = self.clone();
-        let cancel_old = history.and_then(move |list: travis::GetBuilds| {
-            let mut futures = Vec::new();
-            let commits = list
-                .commits
-                .iter()
-                .map(|c| (c.id, c))
-                .collect::<HashMap<_, _>>();
+    /// Checks `branch` of `repo` on `provider`, logging rather than returning
+    /// any failure so that it can't stop the checks of everything else.
+    fn check_isolated(&self, provider: Rc<CiProvider>, repo: Repo, branch: &str) -> MyFuture<()> {
+        let (name, repo_name, branch2) = (provider.name(), repo.full_name(), branch.to_string());
+        let check = self.check_repo(provider, repo, branch).then(move |result| {
+            let context = || BorsErrorKind::Check(name, repo_name.clone(), branch2.clone());
+            if let Err(e) = result.chain_err(context) {
+                error!(
+                    {
+                        provider: name,
+                        repo: repo_name,
+                        branch: branch2,
+                        error: log::chain(&e),
+                    },
+                    "check failed"
+                );
+            }
+            Ok(())
+        });
+        Box::new(check)
+    }
 
-            // we're only interested in builds that concern our branch
-            let builds = list
-                .builds
+    fn check_repo(&self, provider: Rc<CiProvider>, repo: Repo, branch: &str) -> MyFuture<()> {
+        let pattern = match Pattern::new(branch) {
+            Ok(pattern) => pattern,
+            Err(e) => {
+                let kind = BorsErrorKind::InvalidPattern(branch.to_string(), e.to_string());
+                return Box::new(futures::failed(kind.into()));
+            }
+        };
+        // patterns can't be filtered on by the providers themselves, so list
+        // builds on all branches and pick out the matching ones here
+        let filter = if is_pattern(branch) {
+            None
+        } else {
+            Some(branch)
+        };
+        let history = provider.builds(&repo, filter);
+
+        // find out where the branches with running builds are at, after
+        // listing the builds so that none of them are for a newer commit...
+        let heads = self.heads.clone();
+        let repo2 = repo.clone();
+        let metrics = self.metrics.clone();
+        let name = provider.name();
+        let history = history.and_then(move |builds| {
+            let builds = builds
+                .into_iter()
+                .filter(|build| pattern.matches(&build.branch))
+                .collect::<Vec<_>>();
+            let labels = [("provider", name), ("repo", &repo2.full_name()[..])];
+            metrics.add(
+                "cancelbot_builds_inspected_total",
+                &labels,
+                builds.len() as f64,
+            );
+            let mut branches = builds
                 .iter()
-                .filter(|build| match commits.get(&build.commit_id) {
-                    Some(c) if c.branch != me.branch => false,
-                    Some(_) => true,
-                    None => false,
+                .filter(|build| build.status.is_running() && build.commit.is_some())
+                .map(|build| build.branch.clone())
+                .collect::<Vec<_>>();
+            branches.sort();
+            branches.dedup();
+            let lookups = branches
+                .into_iter()
+                .map(|branch| {
+                    let repo_name = repo2.full_name();
+                    heads.head(&repo2, &branch).then(move |head| match head {
+                        Ok(head) => Ok(head.map(|head| (branch, head))),
+                        Err(e) => {
+                            warn!(
+                                {
+                                    provider: name,
+                                    repo: repo_name,
+                                    branch: branch,
+                                    error: log::chain(&e),
+                                },
+                                "failed to find the head of the branch"
+                            );
+                            Ok(None)
+                        }
+                    })
                 })
                 .collect::<Vec<_>>();
+            futures::collect(lookups).map(|heads| {
+                let heads = heads
+                    .into_iter()
+                    .filter_map(|h| h)
+                    .collect::<HashMap<_, _>>();
+                (builds, heads)
+            })
+        });
 
-            // figure out what the max build number is, then cancel everything
-            // that came before that.
-            let max = builds
-                .iter()
-                .map(|b| b.number.parse::<usize>().unwrap())
-                .max();
-            for build in builds.iter() {
-                if !me.travis_build_running(build) {
-                    continue;
-                }
-                if build.number == max.unwrap_or(0).to_string() {
-                    futures.push(me.travis_cancel_if_jobs_failed(build));
-                } else {
-                    println!(
-                        "travis cancelling {} in {} as it's not the latest",
-                        build.number, build.state
+        // ... fetch the jobs of the builds the policy needs to know about,
+        // unless the provider's rate limit is running out, as cancelling
+        // superseded builds matters more...
+        let provider2 = provider.clone();
+        let repo2 = repo.clone();
+        let session = self.session.clone();
+        let snapshot = history.and_then(move |(builds, heads)| {
+            let mut latest = policy::latest_running(&builds, &heads);
+            if let Some(budget) = provider2.budget(&repo2) {
+                if budget.is_low() && !latest.is_empty() {
+                    warn!(
+                        { provider: provider2.name(), repo: repo2.full_name() },
+                        "not fetching jobs of {} build(s) as the rate limit is low: {}",
+                        latest.len(),
+                        budget
                     );
-                    futures.push(me.travis_cancel_build(build));
+                    latest.clear();
                 }
             }
-            futures::collect(futures)
+            let jobs = latest
+                .into_iter()
+                .map(|build| {
+                    let id = build.id.clone();
+                    let (name, repo_name) = (provider2.name(), repo2.full_name());
+                    let branch = build.branch.clone();
+                    provider2.jobs(&repo2, build).then(move |jobs| match jobs {
+                        Ok(jobs) => Ok((id, jobs)),
+                        Err(e) => {
+                            warn!(
+                                {
+                                    provider: name,
+                                    repo: repo_name,
+                                    branch: branch,
+                                    build: id,
+                                    error: log::chain(&e),
+                                },
+                                "failed to fetch jobs"
+                            );
+                            Ok((id, Vec::new()))
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+            futures::collect(jobs).map(|jobs| policy::Snapshot {
+                builds: builds,
+                heads: heads,
+                jobs: jobs.into_iter().collect(),
+            })
         });
 
-        Box::new(cancel_old.map(|_| ()))
-    }
-
-    fn travis_cancel_if_jobs_failed(&self, build: &travis::Build) -> MyFuture<()> {
-        let url = format!("/builds/{}", build.id);
-        let build = http::travis_get(&self.session, &url, &self.travis_token);
+        // ... and then carry out whatever it decides
         let me = self.clone();
-        let cancel = build.and_then(move |b: travis::GetBuild| {
-            let cancel = b.jobs.iter().any(|job| match &job.state[..] {
-                "failed" | "errored" | "canceled" => true,
-                _ => false,
-            });
-
-            if cancel {
-                println!("cancelling top build {} as a job failed", b.build.number);
-                me.travis_cancel_build(&b.build)
-            } else {
-                Box::new(futures::finished(()))
-            }
+        let cancel = snapshot.and_then(move |snapshot| {
+            // a failed cancellation is no reason not to try the others
+            let cancels = policy::decide(&snapshot)
+                .iter()
+                .map(|action| {
+                    let (name, repo_name) = (provider.name(), repo.full_name());
+                    let (branch, build) = (action.build.branch.clone(), action.build.id.clone());
+                    me.cancel(&*provider, &repo, action).then(move |result| {
+                        if let Err(e) = result {
+                            error!(
+                                {
+                                    provider: name,
+                                    repo: repo_name,
+                                    branch: branch,
+                                    build: build,
+                                    error: log::chain(&e),
+                                },
+                                "failed to cancel"
+                            );
+                        }
+                        Ok(())
+                    })
+                })
+                .collect::<Vec<_>>();
+            futures::collect(cancels)
         });
 
         Box::new(cancel.map(|_| ()))
     }
 
-    fn travis_build_running(&self, build: &travis::Build) -> bool {
-        match &build.state[..] {
-            "passed" | "failed" | "canceled" | "errored" => false,
-            _ => true,
-        }
-    }
-
-    fn travis_cancel_build(&self, build: &travis::Build) -> MyFuture<()> {
-        let url = format!("/builds/{}/cancel", build.id);
-        http::travis_post(&self.session, &url, &self.travis_token)
-    }
-
-    fn check_appveyor(&self) -> MyFuture<()> {
-        let futures = self
-            .repos
-            .iter()
-            .map(|repo| self.check_appveyor_repo(repo.clone()))
-            .collect::<Vec<_>>();
-        Box::new(futures::collect(futures).map(|_| ()))
-    }
-
-    fn check_appveyor_repo(&self, repo: Repo) -> MyFuture<()> {
-        let url = format!(
-            "/projects/{}/{}/history?recordsNumber=10&branch={}",
-            self.appveyor_account_name, repo.name, self.branch
-        );
-        let history = http::appveyor_get(&self.session, &url, &self.appveyor_token);
-
-        let me = self.clone();
-        let repo2 = repo.clone();
-        let cancel_old = history.and_then(move |history: appveyor::History| {
-            let max = history.builds.iter().map(|b| b.buildNumber).max();
-            let mut futures = Vec::new();
-            for build in history.builds.iter() {
-                if !me.appveyor_build_running(build) {
-                    continue;
-                }
-                if build.buildNumber < max.unwrap_or(0) {
-                    println!(
-                        "appveyor cancelling {} as it's not the latest",
-                        build.buildNumber
-                    );
-                    futures.push(me.appveyor_cancel_build(&repo2, build));
-                }
-            }
-            futures::collect(futures)
-        });
-
-        let me = self.clone();
-        let url = format!(
-            "/projects/{}/{}/branch/{}",
-            self.appveyor_account_name, repo.name, self.branch
+    /// Cancels a build, or in a dry run only records that it would have been.
+    fn cancel(&self, provider: &CiProvider, repo: &Repo, action: &Action) -> MyFuture<()> {
+        let build = &action.build;
+        let description = format!(
+            "{} build {} of {} on {} as {}",
+            provider.name(),
+            build.number,
+            repo.full_name(),
+            build.branch,
+            action.reason
         );
-        let last_build = http::appveyor_get(&self.session, &url, &self.appveyor_token);
-        let me = me.clone();
-        let cancel_if_failed = last_build.and_then(move |last: appveyor::LastBuild| {
-            if !me.appveyor_build_running(&last.build) {
-                return Box::new(futures::finished(())) as Box<_>;
-            }
-            for job in last.build.jobs.iter() {
-                match &job.status[..] {
-                    "success" | "queued" | "starting" | "running" => continue,
-                    _ => {}
-                }
-
-                println!(
-                    "appveyor cancelling {} as a job is {}",
-                    last.build.buildNumber, job.status
-                );
-                return me.appveyor_cancel_build(&repo, &last.build);
-            }
-            Box::new(futures::finished(()))
+        let (name, repo_name) = (provider.name(), repo.full_name());
+        let reason = action.reason.kind();
+        let log = |action: &str, verb: &str| {
+            info!(
+                {
+                    provider: name,
+                    repo: repo_name,
+                    branch: build.branch,
+                    build: build.number,
+                    action: action,
+                    reason: reason,
+                },
+                "{} {}",
+                verb,
+                description
+            );
+        };
+        let entry = audit::Entry {
+            timestamp: audit::now(),
+            provider: name.to_string(),
+            repo: repo_name.clone(),
+            branch: build.branch.clone(),
+            build: build.id.clone(),
+            number: build.number,
+            commit: build.commit.clone(),
+            reason: reason.to_string(),
+            detail: action.reason.to_string(),
+            dry_run: self.dry_run,
+        };
+        if self.dry_run {
+            log("would_cancel", "would cancel");
+            record(&self.audit, entry);
+            let labels = [
+                ("provider", name),
+                ("repo", &repo_name[..]),
+                ("reason", reason),
+                ("dry_run", "true"),
+            ];
+            self.metrics
+                .inc("cancelbot_builds_cancelled_total", &labels);
+            self.would_cancel.borrow_mut().push(description);
+            return Box::new(futures::finished(()));
+        }
+        log("cancel", "cancelling");
+        let metrics = self.metrics.clone();
+        let build_minutes = self.build_minutes;
+        let audit = self.audit.clone();
+        let cancel = provider.cancel(repo, build).map(move |()| {
+            record(&audit, entry);
+            let labels = [
+                ("provider", name),
+                ("repo", &repo_name[..]),
+                ("reason", reason),
+                ("dry_run", "false"),
+            ];
+            metrics.inc("cancelbot_builds_cancelled_total", &labels);
+            let labels = [("provider", name), ("repo", &repo_name[..])];
+            metrics.add("cancelbot_ci_minutes_saved_total", &labels, build_minutes);
         });
-
-        Box::new(cancel_old.join(cancel_if_failed).map(|_| ()))
+        Box::new(cancel)
     }
 
-    fn appveyor_build_running(&self, build: &appveyor::Build) -> bool {
-        match &build.status[..] {
-            "failed" | "cancelled" | "success" => false,
-            _ => true,
+    /// Prints the rate limit budgets providers have reported, to size
+    /// `--interval` by.
+    fn report_budgets(&self) {
+        for (provider, budget) in self.session.budgets() {
+            info!({ provider: provider }, "rate limit: {}", budget);
         }
     }
 
-    fn appveyor_cancel_build(&self, repo: &Repo, build: &appveyor::Build) -> MyFuture<()> {
-        let url = format!(
-            "/builds/{}/{}/{}",
-            self.appveyor_account_name, repo.name, build.version
+    /// Prints everything a dry run would have cancelled since the last
+    /// report.
+    fn report_dry_run(&self) {
+        if !self.dry_run {
+            return;
+        }
+        let mut would_cancel = self.would_cancel.borrow_mut();
+        info!(
+            "dry run, would have cancelled {} build(s)",
+            would_cancel.len()
         );
-        http::appveyor_delete(&self.session, &url, &self.appveyor_token)
-    }
-
-    fn check_azure_pipelines(&self) -> MyFuture<()> {
-        let futures = self
-            .repos
-            .iter()
-            .map(|repo| self.check_azure_pipelines_repo(repo.clone()))
-            .collect::<Vec<_>>();
-        Box::new(futures::collect(futures).map(|_| ()))
+        for description in would_cancel.drain(..) {
+            info!("  {}", description);
+        }
     }
+}
 
-    fn check_azure_pipelines_repo(&self, repo: Repo) -> MyFuture<()> {
-        let url = format!(
-            "/{}/{}/_apis/build/builds?api-version=5.0&branch=refs/heads/{}",
-            repo.user, repo.name, self.branch,
+/// Appends `entry` to the audit log, if there is one.
+fn record(audit: &Option<Rc<audit::AuditLog>>, entry: audit::Entry) {
+    let audit = match *audit {
+        Some(ref audit) => audit,
+        None => return,
+    };
+    if let Err(e) = audit.record(&entry) {
+        error!(
+            {
+                provider: entry.provider,
+                repo: entry.repo,
+                build: entry.build,
+                error: log::chain(&e),
+            },
+            "failed to record cancellation"
         );
-        let history = http::azure_pipelines_get(&self.session, &url, &self.azure_pipelines_token);
-
-        let me = self.clone();
-        let repo2 = repo.clone();
-        let cancel_old = history.and_then(move |list: azure::List| {
-            let max = list.value.iter().map(|b| b.id).max();
-            let mut futures = Vec::new();
-            for build in list.value.iter() {
-                if !me.azure_build_running(build) {
-                    continue;
-                }
-                if build.id < max.unwrap_or(0) {
-                    println!(
-                        "azure cancelling {} as it's not the latest",
-                        build.id,
-                    );
-                    futures.push(me.azure_cancel_build(&repo2, build));
-                }
-            }
-            futures::collect(futures)
-        });
+    }
+}
 
-        Box::new(cancel_old.map(|_| ()))
+/// Splits `user/name` into its two parts.
+fn parse_repo(name: &str) -> BorsResult<(&str, &str)> {
+    let mut parts = name.splitn(2, '/');
+    match (parts.next(), parts.next()) {
+        (Some(user), Some(repo)) if !user.is_empty() && !repo.is_empty() => Ok((user, repo)),
+        _ => Err(BorsErrorKind::InvalidRepo(name.to_string()).into()),
     }
+}
 
-    fn azure_build_running(&self, build: &azure::Build) -> bool {
-        match &build.status[..] {
-            "cancelling" | "completed" => false,
-            _ => true,
+/// Parses a `--buildkite-pipeline` argument, `user/name=organization/pipeline`.
+fn parse_pipeline(arg: &str) -> BorsResult<(&str, buildkite::Pipeline)> {
+    let mut parts = arg.splitn(2, '=');
+    let (name, slug) = match (parts.next(), parts.next()) {
+        (Some(name), Some(slug)) => (name, slug),
+        _ => return Err(BorsErrorKind::InvalidPipeline(arg.to_string()).into()),
+    };
+    let mut slug = slug.splitn(2, '/');
+    match (slug.next(), slug.next()) {
+        (Some(organization), Some(pipeline))
+            if !organization.is_empty() && !pipeline.is_empty() =>
+        {
+            let pipeline = buildkite::Pipeline {
+                organization: organization.to_string(),
+                pipeline: pipeline.to_string(),
+            };
+            Ok((name, pipeline))
         }
+        _ => Err(BorsErrorKind::InvalidPipeline(arg.to_string()).into()),
     }
+}
 
-    fn azure_cancel_build(&self, repo: &Repo, build: &azure::Build) -> MyFuture<()> {
-        let url = format!(
-            "/{}/{}/_apis/build/builds/{}?api-version=5.0",
-            repo.user, repo.name, build.id,
-        );
-        let body = "{\"status\":\"Cancelling\"}";
-        http::azure_patch(&self.session, &url, &self.azure_pipelines_token, body)
-
-    }
+/// Whether `branch` is a glob pattern like `release/*` rather than the name of
+/// a single branch.
+fn is_pattern(branch: &str) -> bool {
+    branch.contains(|c| c == '*' || c == '?' || c == '[')
 }
@@ -0,0 +1,184 @@
+//! Counters and histograms of what cancelbot has been up to, served in the
+//! Prometheus text format at `/metrics` while running as a daemon.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// `(name, help, type)` of every metric, in the order they're rendered.
+static METRICS: &[(&str, &str, &str)] = &[
+    (
+        "cancelbot_builds_inspected_total",
+        "Builds looked at while checking a branch.",
+        "counter",
+    ),
+    (
+        "cancelbot_builds_cancelled_total",
+        "Builds cancelled, or that would have been in a dry run.",
+        "counter",
+    ),
+    (
+        "cancelbot_ci_minutes_saved_total",
+        "Estimated CI minutes saved by cancelling builds, see --build-minutes.",
+        "counter",
+    ),
+    (
+        "cancelbot_check_timeouts_total",
+        "Checks which were cut short by the 30s timeout.",
+        "counter",
+    ),
+    (
+        "cancelbot_http_errors_total",
+        "Failed HTTP requests, by kind of failure.",
+        "counter",
+    ),
+    (
+        "cancelbot_http_request_duration_seconds",
+        "Time taken by each attempt at an HTTP request.",
+        "histogram",
+    ),
+    (
+        "cancelbot_rate_limit_remaining",
+        "Requests left in a provider's rate limit, as last reported by it.",
+        "gauge",
+    ),
+];
+
+static BUCKETS: &[f64] = &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+type Labels = Vec<(&'static str, String)>;
+
+#[derive(Default)]
+pub struct Metrics {
+    values: RefCell<BTreeMap<(&'static str, Labels), f64>>,
+    histograms: RefCell<BTreeMap<(&'static str, Labels), Histogram>>,
+}
+
+#[derive(Default)]
+struct Histogram {
+    /// Observations per bucket of `BUCKETS`, not yet cumulative.
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Metrics {
+    pub fn inc(&self, name: &'static str, labels: &[(&'static str, &str)]) {
+        self.add(name, labels, 1.0);
+    }
+
+    pub fn add(&self, name: &'static str, labels: &[(&'static str, &str)], value: f64) {
+        let mut values = self.values.borrow_mut();
+        *values.entry((name, owned(labels))).or_insert(0.0) += value;
+    }
+
+    pub fn set(&self, name: &'static str, labels: &[(&'static str, &str)], value: f64) {
+        self.values
+            .borrow_mut()
+            .insert((name, owned(labels)), value);
+    }
+
+    pub fn observe(&self, name: &'static str, labels: &[(&'static str, &str)], value: f64) {
+        let mut histograms = self.histograms.borrow_mut();
+        let histogram = histograms.entry((name, owned(labels))).or_default();
+        if histogram.buckets.is_empty() {
+            histogram.buckets = vec![0; BUCKETS.len()];
+        }
+        if let Some(i) = BUCKETS.iter().position(|&le| value <= le) {
+            histogram.buckets[i] += 1;
+        }
+        histogram.sum += value;
+        histogram.count += 1;
+    }
+
+    /// Renders everything recorded so far in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let values = self.values.borrow();
+        let histograms = self.histograms.borrow();
+        let mut out = String::new();
+        for &(metric, help, kind) in METRICS {
+            let _ = writeln!(out, "# HELP {} {}", metric, help);
+            let _ = writeln!(out, "# TYPE {} {}", metric, kind);
+            for (&(_, ref labels), value) in values.iter().filter(|e| (e.0).0 == metric) {
+                let _ = writeln!(out, "{}{} {}", metric, render_labels(labels, None), value);
+            }
+            for (&(_, ref labels), h) in histograms.iter().filter(|e| (e.0).0 == metric) {
+                let mut cumulative = 0;
+                for (le, count) in BUCKETS.iter().zip(&h.buckets) {
+                    cumulative += count;
+                    let labels = render_labels(labels, Some(&le.to_string()));
+                    let _ = writeln!(out, "{}_bucket{} {}", metric, labels, cumulative);
+                }
+                let inf = render_labels(labels, Some("+Inf"));
+                let _ = writeln!(out, "{}_bucket{} {}", metric, inf, h.count);
+                let labels = render_labels(labels, None);
+                let _ = writeln!(out, "{}_sum{} {}", metric, labels, h.sum);
+                let _ = writeln!(out, "{}_count{} {}", metric, labels, h.count);
+            }
+        }
+        out
+    }
+}
+
+fn owned(labels: &[(&'static str, &str)]) -> Labels {
+    labels.iter().map(|&(k, v)| (k, v.to_string())).collect()
+}
+
+fn render_labels(labels: &Labels, le: Option<&str>) -> String {
+    let mut pairs = labels
+        .iter()
+        .map(|&(k, ref v)| format!("{}=\"{}\"", k, escape(v)))
+        .collect::<Vec<_>>();
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{}\"", le));
+    }
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counters_are_rendered_with_labels() {
+        let metrics = Metrics::default();
+        let labels = [("provider", "travis"), ("repo", "a/\"b\"")];
+        metrics.inc("cancelbot_builds_cancelled_total", &labels);
+        metrics.add("cancelbot_builds_cancelled_total", &labels, 2.0);
+        metrics.inc("cancelbot_check_timeouts_total", &[]);
+        let out = metrics.render();
+        assert!(out.contains("# TYPE cancelbot_builds_cancelled_total counter\n"));
+        assert!(out.contains(
+            "cancelbot_builds_cancelled_total{provider=\"travis\",repo=\"a/\\\"b\\\"\"} 3\n"
+        ));
+        assert!(out.contains("cancelbot_check_timeouts_total 1\n"));
+    }
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let metrics = Metrics::default();
+        let labels = [("provider", "travis")];
+        let name = "cancelbot_http_request_duration_seconds";
+        metrics.observe(name, &labels, 0.2);
+        metrics.observe(name, &labels, 3.0);
+        metrics.observe(name, &labels, 60.0);
+        let out = metrics.render();
+        let bucket = |le: &str| format!("{}_bucket{{provider=\"travis\",le=\"{}\"}}", name, le);
+        assert!(out.contains(&format!("{} 0\n", bucket("0.1"))));
+        assert!(out.contains(&format!("{} 1\n", bucket("0.25"))));
+        assert!(out.contains(&format!("{} 2\n", bucket("5"))));
+        assert!(out.contains(&format!("{} 3\n", bucket("+Inf"))));
+        assert!(out.contains(&format!("{}_count{{provider=\"travis\"}} 3\n", name)));
+    }
+}
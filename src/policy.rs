@@ -0,0 +1,575 @@
+//! Deciding which builds to cancel.
+//!
+//! Everything in here is pure: providers fetch the builds (and the jobs of
+//! the builds `latest_running` asks for) and execute the returned actions,
+//! but what to cancel is decided solely from these snapshots.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::fmt;
+
+use provider::{Build, Job, Status};
+
+/// The builds of a repository relevant to one check, along with the jobs of
+/// the builds returned by `latest_running`.
+pub struct Snapshot {
+    pub builds: Vec<Build>,
+    /// Head commits keyed by branch. Branches missing from here fall back to
+    /// comparing build numbers.
+    pub heads: HashMap<String, String>,
+    /// Jobs keyed by build id. Builds missing from here are assumed to have
+    /// no failed jobs.
+    pub jobs: HashMap<String, Vec<Job>>,
+}
+
+/// A build which should be cancelled.
+#[derive(Clone, Debug)]
+pub struct Action {
+    pub build: Build,
+    pub reason: Reason,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Reason {
+    /// A newer build, with this number, exists for the same branch and
+    /// workflow.
+    Superseded { by: u64 },
+    /// The build's commit is no longer the head of its branch, which is now
+    /// at this commit.
+    Outdated { head: String },
+    /// This is the latest build but one of its jobs already failed, so it
+    /// can't succeed anymore.
+    JobFailed { job: String, status: Status },
+}
+
+impl Reason {
+    /// Short name of the kind of reason, for grouping cancellations by.
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            Reason::Superseded { .. } => "superseded",
+            Reason::Outdated { .. } => "outdated",
+            Reason::JobFailed { .. } => "job_failed",
+        }
+    }
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Reason::Superseded { by } => write!(f, "it's superseded by {}", by),
+            Reason::Outdated { ref head } => write!(f, "the branch has moved on to {}", head),
+            Reason::JobFailed {
+                ref job,
+                ref status,
+            } => write!(f, "job {} is {:?}", job, status),
+        }
+    }
+}
+
+/// Returns the head of the branch if `build` is known to be for some other
+/// commit.
+fn outdated<'a>(build: &Build, heads: &'a HashMap<String, String>) -> Option<&'a String> {
+    match (heads.get(&build.branch), &build.commit) {
+        (Some(head), &Some(ref commit)) if commit != head => Some(head),
+        _ => None,
+    }
+}
+
+/// Builds are only ever superseded by builds of the same branch and workflow,
+/// and only builds of the branch's head can be the latest.
+fn latest_numbers<'a>(
+    builds: &'a [Build],
+    heads: &HashMap<String, String>,
+) -> HashMap<(&'a str, Option<&'a str>), u64> {
+    let mut max = HashMap::new();
+    for build in builds.iter().filter(|b| outdated(b, heads).is_none()) {
+        let key = (&build.branch[..], build.workflow.as_ref().map(|w| &w[..]));
+        let number = max.entry(key).or_insert(build.number);
+        *number = cmp::max(*number, build.number);
+    }
+    max
+}
+
+fn latest_number(max: &HashMap<(&str, Option<&str>), u64>, build: &Build) -> u64 {
+    let key = (&build.branch[..], build.workflow.as_ref().map(|w| &w[..]));
+    max[&key]
+}
+
+/// Returns the builds which are both the latest of their branch and workflow
+/// and still running, whose jobs need to be part of the snapshot.
+pub fn latest_running<'a>(builds: &'a [Build], heads: &HashMap<String, String>) -> Vec<&'a Build> {
+    let max = latest_numbers(builds, heads);
+    builds
+        .iter()
+        .filter(|build| build.status.is_running())
+        .filter(|build| outdated(build, heads).is_none())
+        .filter(|build| build.number == latest_number(&max, build))
+        .collect()
+}
+
+/// Decides which of the builds in `snapshot` to cancel.
+///
+/// Running builds which aren't the latest of their branch and workflow are
+/// cancelled, as their results no longer matter. Where the head of the branch
+/// is known, that's every build of another commit, with build numbers only
+/// deciding between builds of the head itself. The latest builds are
+/// cancelled as soon as one of their jobs has failed.
+pub fn decide(snapshot: &Snapshot) -> Vec<Action> {
+    let max = latest_numbers(&snapshot.builds, &snapshot.heads);
+    let mut actions = Vec::new();
+    for build in snapshot.builds.iter() {
+        if !build.status.is_running() {
+            continue;
+        }
+        if let Some(head) = outdated(build, &snapshot.heads) {
+            actions.push(Action {
+                build: build.clone(),
+                reason: Reason::Outdated { head: head.clone() },
+            });
+            continue;
+        }
+        let latest = latest_number(&max, build);
+        let reason = if build.number != latest {
+            Reason::Superseded { by: latest }
+        } else {
+            let jobs = snapshot.jobs.get(&build.id).map(|j| &j[..]).unwrap_or(&[]);
+            match jobs.iter().find(|job| job.status.is_failure()) {
+                Some(job) => Reason::JobFailed {
+                    job: job.id.clone(),
+                    status: job.status,
+                },
+                None => continue,
+            }
+        };
+        actions.push(Action {
+            build: build.clone(),
+            reason: reason,
+        });
+    }
+    actions
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use provider::{Build, Job, Status};
+
+    fn build(id: &str, number: u64, status: Status) -> Build {
+        Build {
+            id: id.to_string(),
+            number: number,
+            branch: "auto".to_string(),
+            commit: None,
+            workflow: None,
+            status: status,
+        }
+    }
+
+    fn at(mut build: Build, commit: &str) -> Build {
+        build.commit = Some(commit.to_string());
+        build
+    }
+
+    fn on_branch(mut build: Build, branch: &str) -> Build {
+        build.branch = branch.to_string();
+        build
+    }
+
+    fn in_workflow(mut build: Build, workflow: &str) -> Build {
+        build.workflow = Some(workflow.to_string());
+        build
+    }
+
+    fn job(id: &str, status: Status) -> Job {
+        Job {
+            id: id.to_string(),
+            status: status,
+        }
+    }
+
+    fn snapshot(builds: Vec<Build>, jobs: Vec<(&str, Vec<Job>)>) -> Snapshot {
+        Snapshot {
+            builds: builds,
+            heads: HashMap::new(),
+            jobs: jobs
+                .into_iter()
+                .map(|(id, jobs)| (id.to_string(), jobs))
+                .collect::<HashMap<_, _>>(),
+        }
+    }
+
+    fn decisions(snapshot: &Snapshot) -> Vec<(String, Reason)> {
+        decide(snapshot)
+            .into_iter()
+            .map(|action| (action.build.id, action.reason))
+            .collect()
+    }
+
+    fn with_head(mut snapshot: Snapshot, branch: &str, head: &str) -> Snapshot {
+        snapshot.heads.insert(branch.to_string(), head.to_string());
+        snapshot
+    }
+
+    fn ids(builds: Vec<&Build>) -> Vec<&str> {
+        builds.iter().map(|b| &b.id[..]).collect()
+    }
+
+    #[test]
+    fn nothing_to_do_without_builds() {
+        let snapshot = snapshot(vec![], vec![]);
+        assert!(decisions(&snapshot).is_empty());
+        assert!(latest_running(&snapshot.builds, &snapshot.heads).is_empty());
+    }
+
+    #[test]
+    fn single_running_build_is_left_alone() {
+        let snapshot = snapshot(vec![build("a", 1, Status::Running)], vec![]);
+        assert!(decisions(&snapshot).is_empty());
+    }
+
+    #[test]
+    fn older_running_builds_are_superseded() {
+        let snapshot = snapshot(
+            vec![
+                build("a", 1, Status::Running),
+                build("b", 2, Status::Queued),
+                build("c", 3, Status::Running),
+            ],
+            vec![],
+        );
+        assert_eq!(
+            decisions(&snapshot),
+            vec![
+                ("a".to_string(), Reason::Superseded { by: 3 }),
+                ("b".to_string(), Reason::Superseded { by: 3 }),
+            ]
+        );
+    }
+
+    #[test]
+    fn finished_builds_are_never_cancelled() {
+        let snapshot = snapshot(
+            vec![
+                build("a", 1, Status::Passed),
+                build("b", 2, Status::Failed),
+                build("c", 3, Status::Canceled),
+                build("d", 4, Status::Running),
+            ],
+            vec![],
+        );
+        assert!(decisions(&snapshot).is_empty());
+    }
+
+    #[test]
+    fn finished_latest_build_still_supersedes() {
+        let snapshot = snapshot(
+            vec![
+                build("a", 1, Status::Running),
+                build("b", 2, Status::Passed),
+            ],
+            vec![],
+        );
+        assert_eq!(
+            decisions(&snapshot),
+            vec![("a".to_string(), Reason::Superseded { by: 2 })]
+        );
+        assert!(latest_running(&snapshot.builds, &snapshot.heads).is_empty());
+    }
+
+    #[test]
+    fn latest_running_only_returns_latest_builds() {
+        let builds = vec![
+            build("a", 1, Status::Running),
+            build("b", 2, Status::Running),
+        ];
+        assert_eq!(ids(latest_running(&builds, &HashMap::new())), vec!["b"]);
+    }
+
+    #[test]
+    fn latest_build_with_failed_job_is_cancelled() {
+        let snapshot = snapshot(
+            vec![build("a", 1, Status::Running)],
+            vec![(
+                "a",
+                vec![job("linux", Status::Passed), job("windows", Status::Failed)],
+            )],
+        );
+        assert_eq!(
+            decisions(&snapshot),
+            vec![(
+                "a".to_string(),
+                Reason::JobFailed {
+                    job: "windows".to_string(),
+                    status: Status::Failed,
+                }
+            )]
+        );
+    }
+
+    #[test]
+    fn latest_build_with_canceled_job_is_cancelled() {
+        let snapshot = snapshot(
+            vec![build("a", 1, Status::Running)],
+            vec![("a", vec![job("linux", Status::Canceled)])],
+        );
+        assert_eq!(
+            decisions(&snapshot),
+            vec![(
+                "a".to_string(),
+                Reason::JobFailed {
+                    job: "linux".to_string(),
+                    status: Status::Canceled,
+                }
+            )]
+        );
+    }
+
+    #[test]
+    fn latest_build_with_healthy_jobs_is_left_alone() {
+        let snapshot = snapshot(
+            vec![build("a", 1, Status::Running)],
+            vec![(
+                "a",
+                vec![
+                    job("linux", Status::Passed),
+                    job("mac", Status::Running),
+                    job("windows", Status::Queued),
+                ],
+            )],
+        );
+        assert!(decisions(&snapshot).is_empty());
+    }
+
+    #[test]
+    fn failed_jobs_of_superseded_builds_are_reported_as_superseded() {
+        let snapshot = snapshot(
+            vec![
+                build("a", 1, Status::Running),
+                build("b", 2, Status::Running),
+            ],
+            vec![("a", vec![job("linux", Status::Failed)])],
+        );
+        assert_eq!(
+            decisions(&snapshot),
+            vec![("a".to_string(), Reason::Superseded { by: 2 })]
+        );
+    }
+
+    #[test]
+    fn finished_latest_build_with_failed_job_is_left_alone() {
+        let snapshot = snapshot(
+            vec![build("a", 1, Status::Failed)],
+            vec![("a", vec![job("linux", Status::Failed)])],
+        );
+        assert!(decisions(&snapshot).is_empty());
+    }
+
+    #[test]
+    fn branches_never_supersede_each_other() {
+        let snapshot = snapshot(
+            vec![
+                on_branch(build("a", 1, Status::Running), "auto"),
+                on_branch(build("b", 2, Status::Running), "try"),
+                on_branch(build("c", 3, Status::Running), "try"),
+            ],
+            vec![],
+        );
+        assert_eq!(
+            decisions(&snapshot),
+            vec![("b".to_string(), Reason::Superseded { by: 3 })]
+        );
+        assert_eq!(
+            ids(latest_running(&snapshot.builds, &snapshot.heads)),
+            vec!["a", "c"]
+        );
+    }
+
+    #[test]
+    fn workflows_never_supersede_each_other() {
+        let snapshot = snapshot(
+            vec![
+                in_workflow(build("a", 7, Status::Running), "ci"),
+                in_workflow(build("b", 2, Status::Running), "docs"),
+            ],
+            vec![],
+        );
+        assert!(decisions(&snapshot).is_empty());
+        assert_eq!(
+            ids(latest_running(&snapshot.builds, &snapshot.heads)),
+            vec!["a", "b"]
+        );
+    }
+
+    #[test]
+    fn builds_sharing_the_latest_number_are_all_latest() {
+        let snapshot = snapshot(
+            vec![
+                build("a", 1, Status::Running),
+                build("b", 2, Status::Running),
+                build("c", 2, Status::Running),
+            ],
+            vec![("c", vec![job("test", Status::Failed)])],
+        );
+        assert_eq!(
+            decisions(&snapshot),
+            vec![
+                ("a".to_string(), Reason::Superseded { by: 2 }),
+                (
+                    "c".to_string(),
+                    Reason::JobFailed {
+                        job: "test".to_string(),
+                        status: Status::Failed,
+                    }
+                ),
+            ]
+        );
+        assert_eq!(
+            ids(latest_running(&snapshot.builds, &snapshot.heads)),
+            vec!["b", "c"]
+        );
+    }
+
+    #[test]
+    fn builds_of_other_commits_than_the_head_are_outdated() {
+        let snapshot = with_head(
+            snapshot(
+                vec![
+                    at(build("a", 1, Status::Running), "old"),
+                    at(build("b", 2, Status::Running), "head"),
+                ],
+                vec![],
+            ),
+            "auto",
+            "head",
+        );
+        assert_eq!(
+            decisions(&snapshot),
+            vec![(
+                "a".to_string(),
+                Reason::Outdated {
+                    head: "head".to_string()
+                }
+            )]
+        );
+        assert_eq!(
+            ids(latest_running(&snapshot.builds, &snapshot.heads)),
+            vec!["b"]
+        );
+    }
+
+    #[test]
+    fn restarted_old_build_is_outdated_despite_its_number() {
+        let snapshot = with_head(
+            snapshot(
+                vec![
+                    at(build("a", 1, Status::Passed), "head"),
+                    at(build("b", 2, Status::Running), "old"),
+                ],
+                vec![],
+            ),
+            "auto",
+            "head",
+        );
+        assert_eq!(
+            decisions(&snapshot),
+            vec![(
+                "b".to_string(),
+                Reason::Outdated {
+                    head: "head".to_string()
+                }
+            )]
+        );
+    }
+
+    #[test]
+    fn builds_are_outdated_before_the_head_has_a_build() {
+        let snapshot = with_head(
+            snapshot(vec![at(build("a", 1, Status::Running), "old")], vec![]),
+            "auto",
+            "head",
+        );
+        assert_eq!(
+            decisions(&snapshot),
+            vec![(
+                "a".to_string(),
+                Reason::Outdated {
+                    head: "head".to_string()
+                }
+            )]
+        );
+        assert!(latest_running(&snapshot.builds, &snapshot.heads).is_empty());
+    }
+
+    #[test]
+    fn numbers_break_ties_between_builds_of_the_head() {
+        let snapshot = with_head(
+            snapshot(
+                vec![
+                    at(build("a", 1, Status::Running), "head"),
+                    at(build("b", 2, Status::Running), "head"),
+                ],
+                vec![],
+            ),
+            "auto",
+            "head",
+        );
+        assert_eq!(
+            decisions(&snapshot),
+            vec![("a".to_string(), Reason::Superseded { by: 2 })]
+        );
+    }
+
+    #[test]
+    fn builds_without_a_commit_fall_back_to_numbers() {
+        let snapshot = with_head(
+            snapshot(
+                vec![
+                    build("a", 1, Status::Running),
+                    build("b", 2, Status::Running),
+                ],
+                vec![],
+            ),
+            "auto",
+            "head",
+        );
+        assert_eq!(
+            decisions(&snapshot),
+            vec![("a".to_string(), Reason::Superseded { by: 2 })]
+        );
+    }
+
+    #[test]
+    fn heads_only_apply_to_their_branch() {
+        let snapshot = with_head(
+            snapshot(
+                vec![
+                    on_branch(at(build("a", 1, Status::Running), "x"), "try"),
+                    on_branch(at(build("b", 2, Status::Running), "head"), "auto"),
+                ],
+                vec![],
+            ),
+            "auto",
+            "head",
+        );
+        assert!(decisions(&snapshot).is_empty());
+    }
+
+    #[test]
+    fn reasons_are_displayed() {
+        assert_eq!(
+            Reason::Superseded { by: 4 }.to_string(),
+            "it's superseded by 4"
+        );
+        let failed = Reason::JobFailed {
+            job: "linux".to_string(),
+            status: Status::Failed,
+        };
+        assert_eq!(failed.to_string(), "job linux is Failed");
+        let outdated = Reason::Outdated {
+            head: "abc123".to_string(),
+        };
+        assert_eq!(outdated.to_string(), "the branch has moved on to abc123");
+    }
+}
@@ -0,0 +1,117 @@
+use futures::future::{self, Loop};
+use futures::Future;
+
+use http::Budget;
+use MyFuture;
+use Repo;
+
+/// Provider-independent classification of the state of a build or job.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Status {
+    Queued,
+    Running,
+    Passed,
+    Failed,
+    Canceled,
+}
+
+impl Status {
+    /// Whether this build or job could still be cancelled.
+    pub fn is_running(&self) -> bool {
+        match *self {
+            Status::Queued | Status::Running => true,
+            Status::Passed | Status::Failed | Status::Canceled => false,
+        }
+    }
+
+    /// Whether this build or job has already finished unsuccessfully.
+    pub fn is_failure(&self) -> bool {
+        match *self {
+            Status::Failed | Status::Canceled => true,
+            Status::Queued | Status::Running | Status::Passed => false,
+        }
+    }
+}
+
+/// A build on a CI service, normalized across providers.
+#[derive(Clone, Debug)]
+pub struct Build {
+    /// Identifier the provider needs to fetch jobs for or cancel this build.
+    pub id: String,
+    /// Monotonically increasing build number, used to find the latest build
+    /// when the head of the branch isn't known.
+    pub number: u64,
+    /// The branch this build is for.
+    pub branch: String,
+    /// SHA of the commit being built, if the provider reports one.
+    pub commit: Option<String>,
+    /// Services which run several independent workflows per push (and number
+    /// each of them separately) put the workflow here; builds of different
+    /// workflows never supersede one another.
+    pub workflow: Option<String>,
+    pub status: Status,
+}
+
+/// A single job of a build, normalized across providers.
+#[derive(Clone, Debug)]
+pub struct Job {
+    pub id: String,
+    pub status: Status,
+}
+
+/// A CI service that cancelbot knows how to inspect and cancel builds on.
+///
+/// Implementations only deal with talking to their service; the decision of
+/// what to cancel is shared between all providers and lives in `State`.
+pub trait CiProvider {
+    /// Short name of this provider, used in log messages.
+    fn name(&self) -> &'static str;
+
+    /// Whether `repo` can be checked here at all, which it can't if there's
+    /// no token for it.
+    fn covers(&self, _repo: &Repo) -> bool {
+        true
+    }
+
+    /// What's left of the rate limit checking `repo` counts against, if the
+    /// provider has told us.
+    fn budget(&self, repo: &Repo) -> Option<Budget>;
+
+    /// Lists the recent builds of `repo` on `branch`, or on any branch if
+    /// `None`, both finished and running.
+    fn builds(&self, repo: &Repo, branch: Option<&str>) -> MyFuture<Vec<Build>>;
+
+    /// Fetches the jobs which make up `build`, leaving out the ones which are
+    /// allowed to fail. Those can't fail the build, so their failures are no
+    /// reason to cancel it either.
+    fn jobs(&self, repo: &Repo, build: &Build) -> MyFuture<Vec<Job>>;
+
+    /// Cancels `build`.
+    fn cancel(&self, repo: &Repo, build: &Build) -> MyFuture<()>;
+}
+
+/// Fetches builds (or jobs) a page at a time through `page`, which is handed
+/// the cursor returned along with the previous page (or `None` for the first
+/// page).
+///
+/// Stops once a page comes back without a cursor, or after `max_pages`
+/// pages. Providers generally stop handing out cursors for builds once a
+/// page has no running builds, as older builds are then unlikely to be
+/// running either.
+pub fn paginate<T, C, F>(max_pages: usize, mut page: F) -> MyFuture<Vec<T>>
+where
+    T: 'static,
+    C: 'static,
+    F: FnMut(Option<C>) -> MyFuture<(Vec<T>, Option<C>)> + 'static,
+{
+    let builds = future::loop_fn((Vec::new(), None, 1), move |(mut all, cursor, pages)| {
+        page(cursor).map(move |(builds, next)| {
+            all.extend(builds);
+            match next {
+                Some(next) if pages < max_pages => Loop::Continue((all, Some(next), pages + 1)),
+                _ => Loop::Break(all),
+            }
+        })
+    });
+    Box::new(builds)
+}
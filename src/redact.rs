@@ -0,0 +1,223 @@
+//! Scrubbing secrets out of anything that ends up in logs or error messages.
+//!
+//! Three kinds of secrets are redacted: the tokens cancelbot was given, which
+//! are registered with `add_secret` as they're read; the values of headers
+//! and query parameters which carry credentials; and tokens recognisable by
+//! their prefix, like GitHub's `ghp_...`, which may be echoed back by a CI
+//! service in a response body.
+
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const REDACTED: &str = "[REDACTED]";
+
+/// Headers whose values are credentials, matched case-insensitively.
+static HEADERS: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "private-token",
+    "circle-token",
+    "travis-api-token",
+    "x-api-key",
+];
+
+/// Query parameters whose values are credentials, matched case-insensitively.
+/// Each is matched as a whole word, so `token` doesn't cover `access_token`.
+static PARAMS: &[&str] = &[
+    "token",
+    "access_token",
+    "private_token",
+    "secret",
+    "password",
+    "circle-token",
+    "key",
+    "api_key",
+];
+
+/// Prefixes of well-known kinds of tokens, along with how many characters
+/// have to follow for it to be a token rather than a word.
+static PREFIXES: &[(&str, usize)] = &[
+    ("ghp_", 20),
+    ("gho_", 20),
+    ("ghu_", 20),
+    ("ghs_", 20),
+    ("ghr_", 20),
+    ("github_pat_", 20),
+    ("glpat-", 20),
+    ("xoxb-", 10),
+    ("xoxp-", 10),
+    ("AKIA", 16),
+];
+
+static MAX_EXCERPT: AtomicUsize = AtomicUsize::new(512);
+
+thread_local! {
+    static SECRETS: RefCell<Vec<String>> = RefCell::new(Vec::new());
+}
+
+/// Redacts `secret` from now on, both as is and the way it's sent with HTTP
+/// basic authentication. However short it is, a secret the user gave us is
+/// never worth leaking.
+pub fn add_secret(secret: &str) {
+    if secret.is_empty() {
+        return;
+    }
+    SECRETS.with(|secrets| {
+        let mut secrets = secrets.borrow_mut();
+        secrets.push(secret.to_string());
+        secrets.push(base64::encode(&format!(":{}", secret)));
+    });
+}
+
+/// Sets how many bytes of a response body `excerpt` keeps.
+pub fn set_max_excerpt(len: usize) {
+    MAX_EXCERPT.store(len, Ordering::SeqCst);
+}
+
+/// Returns `s` with every secret in it replaced by `[REDACTED]`.
+pub fn redact(s: &str) -> String {
+    let mut s = s.to_string();
+    SECRETS.with(|secrets| {
+        for secret in secrets.borrow().iter() {
+            s = s.replace(&secret[..], REDACTED);
+        }
+    });
+    for header in HEADERS {
+        let redacted = format!(" {}", REDACTED);
+        s = replace_values(&s, &format!("{}:", header), &redacted, |c| {
+            c == '\r' || c == '\n' || c == '"'
+        });
+    }
+    for param in PARAMS {
+        s = replace_values(&s, &format!("{}=", param), REDACTED, |c| {
+            c == '&' || c == '"' || c == '\'' || c.is_whitespace()
+        });
+    }
+    for &(prefix, min) in PREFIXES {
+        s = replace_tokens(&s, prefix, min);
+    }
+    s
+}
+
+/// Redacts a response body and cuts it down to a size fit for an error
+/// message.
+pub fn excerpt(body: &str) -> String {
+    excerpt_with(body, MAX_EXCERPT.load(Ordering::SeqCst))
+}
+
+/// Like `excerpt`, cutting the body down to `max` bytes rather than the
+/// configured size.
+fn excerpt_with(body: &str, max: usize) -> String {
+    let body = redact(body);
+    if body.len() <= max {
+        return body;
+    }
+    let mut end = max;
+    while !body.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}... ({} more bytes)", &body[..end], body.len() - end)
+}
+
+/// Replaces what follows each occurrence of `marker`, up to a character
+/// matching `is_end`, with `replacement`.
+fn replace_values<F>(s: &str, marker: &str, replacement: &str, is_end: F) -> String
+where
+    F: Fn(char) -> bool,
+{
+    // lowercasing ASCII leaves byte offsets as they are
+    let lower = s.to_ascii_lowercase();
+    let mut out = String::with_capacity(s.len());
+    let mut pos = 0;
+    while let Some(i) = lower[pos..].find(marker) {
+        let start = pos + i + marker.len();
+        // `token=` shouldn't match `page_token=`, and neither should
+        // `authorization:` match the end of another header
+        let boundary = pos + i == 0 || !is_word(s[..pos + i].chars().next_back().unwrap());
+        let end = s[start..]
+            .find(|c| is_end(c))
+            .map(|j| start + j)
+            .unwrap_or(s.len());
+        out.push_str(&s[pos..start]);
+        if boundary && !s[start..end].trim().is_empty() {
+            out.push_str(replacement);
+        } else {
+            out.push_str(&s[start..end]);
+        }
+        pos = end;
+    }
+    out.push_str(&s[pos..]);
+    out
+}
+
+/// Replaces tokens starting with `prefix` followed by at least `min` token
+/// characters.
+fn replace_tokens(s: &str, prefix: &str, min: usize) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut pos = 0;
+    while let Some(i) = s[pos..].find(prefix) {
+        let start = pos + i;
+        let after = start + prefix.len();
+        let len = s[after..].find(|c| !is_word(c)).unwrap_or(s.len() - after);
+        let boundary = start == 0 || !is_word(s[..start].chars().next_back().unwrap());
+        out.push_str(&s[pos..start]);
+        if boundary && len >= min {
+            out.push_str(REDACTED);
+        } else {
+            out.push_str(&s[start..after + len]);
+        }
+        pos = after + len;
+    }
+    out.push_str(&s[pos..]);
+    out
+}
+
+fn is_word(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registered_secrets_are_redacted() {
+        add_secret("s3cr3t-token-value");
+        add_secret("short");
+        add_secret("");
+        let basic = base64::encode(":s3cr3t-token-value");
+        let s = format!("token s3cr3t-token-value, basic {}, short", basic);
+        assert_eq!(redact(&s), "token [REDACTED], basic [REDACTED], [REDACTED]");
+    }
+
+    #[test]
+    fn credential_headers_and_params_are_redacted() {
+        assert_eq!(
+            redact("Accept: json\r\nAuthorization: Bearer abc\r\nPRIVATE-TOKEN: xyz\r\n"),
+            "Accept: json\r\nAuthorization: [REDACTED]\r\nPRIVATE-TOKEN: [REDACTED]\r\n"
+        );
+        assert_eq!(
+            redact("GET /hook?secret=hunter2&branch=auto&page_token=2"),
+            "GET /hook?secret=[REDACTED]&branch=auto&page_token=2"
+        );
+        assert_eq!(
+            redact("/api?access_token=abc&private_token=def&api_key=ghi"),
+            "/api?access_token=[REDACTED]&private_token=[REDACTED]&api_key=[REDACTED]"
+        );
+    }
+
+    #[test]
+    fn well_known_tokens_are_redacted() {
+        assert_eq!(
+            redact(r#"{"message": "bad credentials ghp_0123456789abcdefghijklmn"}"#),
+            r#"{"message": "bad credentials [REDACTED]"}"#
+        );
+        assert_eq!(redact("ghp_short and AKIA"), "ghp_short and AKIA");
+    }
+
+    #[test]
+    fn excerpts_are_capped() {
+        assert_eq!(excerpt_with("abcdéfgh", 5), "abcd... (5 more bytes)");
+        assert_eq!(excerpt_with("abc", 5), "abc");
+    }
+}
@@ -0,0 +1,395 @@
+//! Webhook listener which checks a repository as soon as something happens
+//! to it, rather than waiting for the next poll.
+//!
+//! `GET /metrics` serves `metrics` in the Prometheus text format. The webhook
+//! endpoints only accept `POST` requests, and only when a secret is set:
+//!
+//! * `/github` - GitHub `push` events, authenticated through the
+//!   `X-Hub-Signature-256` HMAC of the payload.
+//! * `/travis`, `/appveyor` and `/azure` - build notifications from the
+//!   respective services. None of these can sign their payloads with a shared
+//!   secret, so the secret has to be passed as a `?secret=...` query
+//!   parameter in the configured webhook URL instead. Travis additionally
+//!   signs each payload with its own key, which is checked against the one
+//!   its API publishes under `/config`.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::str;
+
+use futures::{Future, Stream};
+use hex;
+use hmac::{Hmac, Mac};
+use hyper::header::{ContentType, Headers};
+use hyper::server::{Http, Request, Response, Service};
+use hyper::{self, Chunk, Method, StatusCode};
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Verifier;
+use rustc_serialize::json::Json;
+use sha2::Sha256;
+use tokio_core::reactor::Handle;
+
+use log;
+use travis;
+use MyFuture;
+use State;
+
+pub fn serve(
+    state: State,
+    addr: &SocketAddr,
+    secret: Option<String>,
+    handle: &Handle,
+) -> MyFuture<()> {
+    let webhooks = Webhooks {
+        state: state,
+        secret: secret.map(Rc::new),
+        travis_keys: Rc::new(RefCell::new(HashMap::new())),
+        handle: handle.clone(),
+    };
+    let http = Http::<Chunk>::new();
+    let connections = match http.serve_addr_handle(addr, handle, move || Ok(webhooks.clone())) {
+        Ok(connections) => connections,
+        Err(e) => return Box::new(futures::failed(e.into())),
+    };
+    info!("listening on {}", addr);
+
+    let handle = handle.clone();
+    let server = connections.for_each(move |connection| {
+        handle.spawn(connection.map(|_| ()).map_err(|e| {
+            warn!("webhook connection failed: {}", e);
+        }));
+        Ok(())
+    });
+    Box::new(server.map_err(From::from))
+}
+
+#[derive(Clone)]
+struct Webhooks {
+    state: State,
+    /// Webhooks are turned away without one.
+    secret: Option<Rc<String>>,
+    /// Keys Travis signs its notifications with, by API root.
+    travis_keys: Rc<RefCell<HashMap<String, String>>>,
+    handle: Handle,
+}
+
+impl Service for Webhooks {
+    type Request = Request;
+    type Response = Response;
+    type Error = hyper::Error;
+    type Future = Box<Future<Item = Response, Error = hyper::Error>>;
+
+    fn call(&self, req: Request) -> Self::Future {
+        let me = self.clone();
+        let (method, uri, _version, headers, body) = req.deconstruct();
+        let response = body.concat2().and_then(move |body| {
+            if method == Method::Get && uri.path() == "/metrics" {
+                let response = Response::new()
+                    .with_header(ContentType("text/plain; version=0.0.4".parse().unwrap()))
+                    .with_body(me.state.metrics.render());
+                return Box::new(futures::finished(response)) as Self::Future;
+            }
+            let reply = if method != Method::Post {
+                reply(
+                    StatusCode::MethodNotAllowed,
+                    "only POST is supported".to_string(),
+                )
+            } else {
+                me.webhook(uri.path(), uri.query(), &headers, body.to_vec())
+            };
+            Box::new(reply.map(move |(status, message)| {
+                info!("webhook {} - {}: {}", uri.path(), status, message);
+                Response::new().with_status(status).with_body(message)
+            }))
+        });
+        Box::new(response)
+    }
+}
+
+type Reply = Box<Future<Item = (StatusCode, String), Error = hyper::Error>>;
+
+fn reply(status: StatusCode, message: String) -> Reply {
+    Box::new(futures::finished((status, message)))
+}
+
+impl Webhooks {
+    fn webhook(&self, path: &str, query: Option<&str>, headers: &Headers, body: Vec<u8>) -> Reply {
+        let secret = match self.secret {
+            Some(ref secret) => secret,
+            None => return reply(StatusCode::NotFound, "webhooks aren't enabled".to_string()),
+        };
+        let authenticated = match path {
+            "/github" => github_signature_valid(secret, headers, &body),
+            "/travis" | "/appveyor" | "/azure" => match query_param(query, "secret") {
+                Some(given) => constant_time_eq(given.as_bytes(), secret.as_bytes()),
+                None => false,
+            },
+            _ => return reply(StatusCode::NotFound, format!("no webhook at {}", path)),
+        };
+        if !authenticated {
+            return reply(StatusCode::Unauthorized, "invalid signature".to_string());
+        }
+
+        let body = match String::from_utf8(body) {
+            Ok(body) => body,
+            Err(_) => return reply(StatusCode::BadRequest, "body is not utf-8".to_string()),
+        };
+        let event = match path {
+            "/github" => github_event(header(headers, "X-GitHub-Event"), &body),
+            "/travis" => travis_event(&body),
+            "/appveyor" => appveyor_event(&body),
+            _ => azure_event(&body),
+        };
+        let (repo, branch) = match event {
+            Ok(Some(event)) => event,
+            Ok(None) => return reply(StatusCode::Ok, "nothing to do for this event".to_string()),
+            Err(msg) => return reply(StatusCode::BadRequest, msg),
+        };
+        if path != "/travis" {
+            let (status, message) = self.trigger(&repo, &branch);
+            return reply(status, message);
+        }
+
+        // anyone who learns the webhook URL can pass the secret along, so
+        // Travis notifications also have to be signed with Travis' own key
+        let signature = header(headers, "Signature").map(|s| s.to_string());
+        let payload = query_param(Some(&body), "payload").unwrap_or_default();
+        let me = self.clone();
+        Box::new(self.travis_key(&repo).then(move |key| {
+            let key = match key {
+                Ok(key) => key,
+                Err(e) => {
+                    error!({ error: log::chain(&e) }, "failed to fetch the travis key");
+                    return Ok((
+                        StatusCode::ServiceUnavailable,
+                        "can't verify the signature right now".to_string(),
+                    ));
+                }
+            };
+            if !travis_signature_valid(&key, signature, &payload) {
+                return Ok((StatusCode::Unauthorized, "invalid signature".to_string()));
+            }
+            Ok(me.trigger(&repo, &branch))
+        }))
+    }
+
+    /// Returns the key notifications about `repo` are signed with, which
+    /// belongs to whichever Travis API it builds on.
+    fn travis_key(&self, repo: &str) -> MyFuture<String> {
+        let state = &self.state;
+        let base_url = state
+            .repos
+            .iter()
+            .find(|r| r.full_name() == repo)
+            .and_then(|r| r.travis.as_ref())
+            .and_then(|c| c.url.clone())
+            .unwrap_or_else(|| state.travis_url.clone());
+        if let Some(key) = self.travis_keys.borrow().get(&base_url) {
+            return Box::new(futures::finished(key.clone()));
+        }
+        let keys = self.travis_keys.clone();
+        Box::new(
+            travis::public_key(&state.session, &base_url).map(move |key| {
+                keys.borrow_mut().insert(base_url, key.clone());
+                key
+            }),
+        )
+    }
+
+    /// Kicks off a check of `branch` in `repo` (given as `user/name`) in the
+    /// background, provided it's something we're watching.
+    fn trigger(&self, repo: &str, branch: &str) -> (StatusCode, String) {
+        let state = &self.state;
+        let repo = match state.repos.iter().find(|r| r.full_name() == repo) {
+            Some(repo) => repo,
+            None => return (StatusCode::NotFound, format!("not watching {}", repo)),
+        };
+        if !repo.watches(branch) {
+            return (StatusCode::Ok, format!("not watching branch {}", branch));
+        }
+
+        self.handle.spawn(
+            state
+                .check_branch(repo, branch, &self.handle)
+                .then(|result| {
+                    if let Err(e) = result {
+                        error!({ error: log::chain(&e) }, "webhook check failed");
+                    }
+                    Ok(())
+                }),
+        );
+        (
+            StatusCode::Accepted,
+            format!("checking {}/{} {}", repo.user, repo.name, branch),
+        )
+    }
+}
+
+fn github_signature_valid(secret: &str, headers: &Headers, body: &[u8]) -> bool {
+    let signature = match header(headers, "X-Hub-Signature-256") {
+        Some(signature) => signature,
+        None => return false,
+    };
+    let signature = match hex::decode(signature.trim_start_matches("sha256=")) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+    let mut mac = match Hmac::<Sha256>::new_varkey(secret.as_bytes()) {
+        Ok(mac) => mac,
+        Err(_) => return false,
+    };
+    mac.input(body);
+    mac.verify(&signature).is_ok()
+}
+
+/// Checks the base64 `signature` of `payload`, an RSA-SHA1 signature made
+/// with the PEM encoded `key`.
+fn travis_signature_valid(key: &str, signature: Option<String>, payload: &str) -> bool {
+    let signature = match signature.map(|s| base64::decode(s.trim())) {
+        Some(Ok(signature)) => signature,
+        _ => return false,
+    };
+    let key = match PKey::public_key_from_pem(key.as_bytes()) {
+        Ok(key) => key,
+        Err(_) => return false,
+    };
+    let mut verifier = match Verifier::new(MessageDigest::sha1(), &key) {
+        Ok(verifier) => verifier,
+        Err(_) => return false,
+    };
+    if verifier.update(payload.as_bytes()).is_err() {
+        return false;
+    }
+    verifier.verify(&signature).unwrap_or(false)
+}
+
+type Event = Result<Option<(String, String)>, String>;
+
+/// Extracts the repository and branch that were pushed to.
+fn github_event(event: Option<&str>, body: &str) -> Event {
+    match event {
+        Some("push") => {}
+        _ => return Ok(None),
+    }
+    let json = try!(parse(body));
+    let branch = match string(&json, &["ref"]) {
+        Some(r) if r.starts_with("refs/heads/") => r["refs/heads/".len()..].to_string(),
+        _ => return Ok(None),
+    };
+    match string(&json, &["repository", "full_name"]) {
+        Some(repo) => Ok(Some((repo.to_string(), branch))),
+        None => Err("push event without a repository".to_string()),
+    }
+}
+
+/// Travis sends its notifications form-encoded, with the JSON itself in the
+/// `payload` field.
+fn travis_event(body: &str) -> Event {
+    let payload = match query_param(Some(body), "payload") {
+        Some(payload) => payload,
+        None => return Err("no payload in notification".to_string()),
+    };
+    let json = try!(parse(&payload));
+    if json.find("pull_request").and_then(|j| j.as_boolean()) == Some(true) {
+        return Ok(None);
+    }
+    let user = string(&json, &["repository", "owner_name"]);
+    let name = string(&json, &["repository", "name"]);
+    let branch = string(&json, &["branch"]);
+    match (user, name, branch) {
+        (Some(user), Some(name), Some(branch)) => {
+            Ok(Some((format!("{}/{}", user, name), branch.to_string())))
+        }
+        _ => Err("notification without a repository or branch".to_string()),
+    }
+}
+
+fn appveyor_event(body: &str) -> Event {
+    let json = try!(parse(body));
+    let repo = string(&json, &["eventData", "repositoryName"]);
+    let branch = string(&json, &["eventData", "branch"]);
+    match (repo, branch) {
+        (Some(repo), Some(branch)) => Ok(Some((repo.to_string(), branch.to_string()))),
+        _ => Err("notification without a repository or branch".to_string()),
+    }
+}
+
+/// Azure Pipelines service hooks for build events; the repository is only
+/// identified as `user/name` for GitHub-hosted repositories.
+fn azure_event(body: &str) -> Event {
+    let json = try!(parse(body));
+    let repo = string(&json, &["resource", "repository", "id"]);
+    let branch = match string(&json, &["resource", "sourceBranch"]) {
+        Some(r) if r.starts_with("refs/heads/") => r["refs/heads/".len()..].to_string(),
+        _ => return Ok(None),
+    };
+    match repo {
+        Some(repo) => Ok(Some((repo.to_string(), branch))),
+        None => Err("notification without a repository".to_string()),
+    }
+}
+
+fn parse(body: &str) -> Result<Json, String> {
+    Json::from_str(body).map_err(|e| format!("invalid json: {}", e))
+}
+
+fn string<'a>(json: &'a Json, path: &[&str]) -> Option<&'a str> {
+    json.find_path(path).and_then(|j| j.as_string())
+}
+
+fn header<'a>(headers: &'a Headers, name: &str) -> Option<&'a str> {
+    headers
+        .get_raw(name)
+        .and_then(|raw| raw.one())
+        .and_then(|value| str::from_utf8(value).ok())
+}
+
+/// Finds and decodes `key` in a `application/x-www-form-urlencoded` string.
+fn query_param(query: Option<&str>, key: &str) -> Option<String> {
+    query?
+        .split('&')
+        .filter_map(|pair| {
+            let mut parts = pair.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(k), Some(v)) if k == key => Some(v),
+                _ => None,
+            }
+        })
+        .next()
+        .map(percent_decode)
+}
+
+fn percent_decode(s: &str) -> String {
+    let mut bytes = Vec::new();
+    let mut iter = s.bytes();
+    while let Some(b) = iter.next() {
+        match b {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex = iter.by_ref().take(2).collect::<Vec<_>>();
+                match str::from_utf8(&hex)
+                    .ok()
+                    .and_then(|h| u8::from_str_radix(h, 16).ok())
+                {
+                    Some(b) => bytes.push(b),
+                    None => {
+                        bytes.push(b'%');
+                        bytes.extend(hex);
+                    }
+                }
+            }
+            b => bytes.push(b),
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
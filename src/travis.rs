@@ -1,7 +1,40 @@
-#[derive(RustcDecodable, Debug)]
-pub struct GetBuilds {
+use futures::Future;
+use rustc_serialize::{Decodable, Decoder};
+
+use errors::*;
+use http::{self, Session};
+use provider::{self, CiProvider, Status};
+use MyFuture;
+use Repo;
+
+/// A page of `/repo/{slug}/builds`.
+#[derive(Debug)]
+pub struct Builds {
+    pub pagination: Pagination,
     pub builds: Vec<Build>,
-    pub commits: Vec<Commit>,
+}
+
+// written out by hand as `@pagination` can't be used as a field name
+impl Decodable for Builds {
+    fn decode<D: Decoder>(d: &mut D) -> Result<Builds, D::Error> {
+        d.read_struct("Builds", 2, |d| {
+            Ok(Builds {
+                pagination: try!(d.read_struct_field("@pagination", 0, Decodable::decode)),
+                builds: try!(d.read_struct_field("builds", 1, Decodable::decode)),
+            })
+        })
+    }
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct Pagination {
+    pub is_last: bool,
+    pub next: Option<Page>,
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct Page {
+    pub offset: u64,
 }
 
 #[derive(RustcDecodable, Debug)]
@@ -9,27 +42,197 @@ pub struct Build {
     pub id: u32,
     pub number: String,
     pub state: String,
-    pub commit_id: u32,
-    pub job_ids: Vec<u32>,
+    pub branch: Branch,
+    pub commit: Commit,
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct Branch {
+    pub name: String,
 }
 
 #[derive(RustcDecodable, Debug)]
 pub struct Commit {
-    pub id: u32,
-    pub branch: String,
+    pub sha: String,
 }
 
 #[derive(RustcDecodable, Debug)]
-pub struct GetBuild {
-    pub commit: Commit,
-    pub build: Build,
+pub struct Jobs {
     pub jobs: Vec<Job>,
 }
 
 #[derive(RustcDecodable, Debug)]
 pub struct Job {
     pub id: u32,
-    pub build_id: u32,
     pub allow_failure: bool,
     pub state: String,
 }
+
+/// The parts of `/config` describing how notifications are sent.
+#[derive(RustcDecodable, Debug)]
+pub struct ConfigResponse {
+    pub config: Config,
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct Config {
+    pub notifications: Notifications,
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct Notifications {
+    pub webhook: Webhook,
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct Webhook {
+    pub public_key: String,
+}
+
+pub struct Travis {
+    pub session: Session,
+    /// Token for repositories without one of their own, if there is one.
+    pub token: Option<String>,
+    /// Root of the Travis API, e.g. `https://api.travis-ci.com`.
+    pub base_url: String,
+    /// Most pages of build history to look through per check.
+    pub max_pages: usize,
+}
+
+impl Travis {
+    /// Returns the API `repo` builds on, which may differ from the default.
+    fn base_url(&self, repo: &Repo) -> String {
+        repo.travis
+            .as_ref()
+            .and_then(|c| c.url.clone())
+            .unwrap_or_else(|| self.base_url.clone())
+    }
+
+    /// Returns the token for `repo`'s instance, its own if it has one. It's
+    /// never empty for the repositories this covers.
+    fn token(&self, repo: &Repo) -> String {
+        repo.travis
+            .as_ref()
+            .and_then(|c| c.token.clone())
+            .or_else(|| self.token.clone())
+            .unwrap_or_default()
+    }
+}
+
+/// Expands the `org` and `com` shorthands for the public Travis APIs.
+pub fn api_url(url: &str) -> String {
+    match url {
+        "org" => "https://api.travis-ci.org".to_string(),
+        "com" => "https://api.travis-ci.com".to_string(),
+        url => url.trim_end_matches('/').to_string(),
+    }
+}
+
+/// Fetches the PEM encoded key which the Travis at `base_url` signs its
+/// webhook notifications with.
+pub fn public_key(session: &Session, base_url: &str) -> MyFuture<String> {
+    let config = http::travis_get_public::<ConfigResponse>(session, base_url, "/config");
+    Box::new(config.map(|c| c.config.notifications.webhook.public_key))
+}
+
+impl CiProvider for Travis {
+    fn name(&self) -> &'static str {
+        "travis"
+    }
+
+    fn covers(&self, repo: &Repo) -> bool {
+        self.token.is_some() || repo.travis.as_ref().map_or(false, |c| c.token.is_some())
+    }
+
+    fn budget(&self, repo: &Repo) -> Option<http::Budget> {
+        self.session.budget(self.name(), &self.token(repo))
+    }
+
+    fn builds(&self, repo: &Repo, branch: Option<&str>) -> MyFuture<Vec<provider::Build>> {
+        let session = self.session.clone();
+        let token = self.token(repo);
+        let base_url = self.base_url(repo);
+        let repo_name = repo.full_name();
+        // builds in every state, as a restarted old build is superseded by
+        // newer ones even once they've finished
+        let mut base = format!(
+            "/repo/{}%2F{}/builds?sort_by=number:desc",
+            repo.user, repo.name,
+        );
+        if let Some(branch) = branch {
+            base.push_str(&format!("&branch.name={}", branch));
+        }
+
+        provider::paginate(self.max_pages, move |offset: Option<u64>| {
+            let url = match offset {
+                Some(offset) => format!("{}&offset={}", base, offset),
+                None => base.clone(),
+            };
+            let history = http::travis_get(&session, &base_url, &url, &token);
+            let repo_name = repo_name.clone();
+            let page = history.and_then(move |list: Builds| {
+                let next = match list.pagination.next {
+                    Some(ref page) if !list.pagination.is_last => Some(page.offset),
+                    _ => None,
+                };
+                let builds = try!(list
+                    .builds
+                    .iter()
+                    .map(|build| {
+                        let number = try!(build.number.parse().chain_err(|| {
+                            BorsErrorKind::InvalidBuild(
+                                "travis",
+                                repo_name.clone(),
+                                build.id.to_string(),
+                                format!("build number `{}` isn't a number", build.number),
+                            )
+                        }));
+                        Ok(provider::Build {
+                            id: build.id.to_string(),
+                            number: number,
+                            branch: build.branch.name.clone(),
+                            commit: Some(build.commit.sha.clone()),
+                            workflow: None,
+                            status: classify(&build.state),
+                        })
+                    })
+                    .collect::<BorsResult<_>>());
+                Ok((builds, next))
+            });
+            Box::new(page)
+        })
+    }
+
+    fn jobs(&self, repo: &Repo, build: &provider::Build) -> MyFuture<Vec<provider::Job>> {
+        let url = format!("/build/{}/jobs", build.id);
+        let base_url = self.base_url(repo);
+        let jobs = http::travis_get(&self.session, &base_url, &url, &self.token(repo));
+        let jobs = jobs.map(move |list: Jobs| {
+            // leave out jobs in `allow_failures`
+            list.jobs
+                .iter()
+                .filter(|job| !job.allow_failure)
+                .map(|job| provider::Job {
+                    id: job.id.to_string(),
+                    status: classify(&job.state),
+                })
+                .collect()
+        });
+        Box::new(jobs)
+    }
+
+    fn cancel(&self, repo: &Repo, build: &provider::Build) -> MyFuture<()> {
+        let url = format!("/build/{}/cancel", build.id);
+        http::travis_post(&self.session, &self.base_url(repo), &url, &self.token(repo))
+    }
+}
+
+fn classify(status: &str) -> Status {
+    match status {
+        "created" | "received" | "queued" => Status::Queued,
+        "passed" => Status::Passed,
+        "failed" | "errored" => Status::Failed,
+        "canceled" => Status::Canceled,
+        _ => Status::Running,
+    }
+}
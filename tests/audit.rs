@@ -0,0 +1,109 @@
+//! Checks that cancellations end up in the audit log and can be found again
+//! with `cancelbot audit`.
+
+mod mock;
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use mock::{cancelbot, route, Server};
+
+fn audit_log(name: &str) -> PathBuf {
+    let path = env::temp_dir().join(format!(
+        "cancelbot-audit-{}-{}.jsonl",
+        name,
+        std::process::id()
+    ));
+    let _ = fs::remove_file(&path);
+    path
+}
+
+fn travis_server() -> Server {
+    Server::start(vec![
+        route(
+            "GET",
+            "/repo/rust-lang%2Frust/builds",
+            r#"{"@pagination": {"is_last": true, "next": null}, "builds": [
+                {"id": 12, "number": "2", "state": "started",
+                 "branch": {"name": "auto"}, "commit": {"sha": "sha2"}},
+                {"id": 11, "number": "1", "state": "started",
+                 "branch": {"name": "auto"}, "commit": {"sha": "sha1"}}]}"#,
+        ),
+        route("GET", "/build/12/jobs", r#"{"jobs": []}"#),
+        route("POST", "/build/11/cancel", "{}"),
+    ])
+}
+
+fn run(server: &Server, log: &PathBuf, extra: &[&str]) {
+    let mut args = vec![
+        "--travis-url",
+        server.url(),
+        "-t",
+        "token",
+        "-b",
+        "auto",
+        "--audit-log",
+        log.to_str().unwrap(),
+    ];
+    args.extend_from_slice(extra);
+    args.push("rust-lang/rust");
+    cancelbot(&args);
+}
+
+fn audit(log: &PathBuf, query: &[&str]) -> String {
+    let mut args = vec!["audit", "--audit-log", log.to_str().unwrap()];
+    args.extend_from_slice(query);
+    let output = cancelbot(&args);
+    assert!(output.status.success());
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn cancellations_are_recorded() {
+    let log = audit_log("recorded");
+    let server = travis_server();
+    run(&server, &log, &[]);
+    assert_eq!(server.mutations(), vec!["POST /build/11/cancel"]);
+
+    let contents = fs::read_to_string(&log).unwrap();
+    let lines = contents.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 1);
+    for field in &[
+        r#""provider":"travis""#,
+        r#""repo":"rust-lang/rust""#,
+        r#""branch":"auto""#,
+        r#""build":"11""#,
+        r#""number":1"#,
+        r#""commit":"sha1""#,
+        r#""reason":"superseded""#,
+        r#""detail":"it's superseded by 2""#,
+        r#""dry_run":false"#,
+    ] {
+        assert!(
+            lines[0].contains(field),
+            "{} missing from {}",
+            field,
+            lines[0]
+        );
+    }
+
+    let out = audit(&log, &["--repo", "rust-lang/rust", "--build", "1"]);
+    assert!(out.contains("cancelled travis build 1 (11) of rust-lang/rust on auto at sha1"));
+    assert!(audit(&log, &["--repo", "rust-lang/cargo"]).is_empty());
+    assert!(audit(&log, &["--until", "2000-01-01"]).is_empty());
+    assert!(!audit(&log, &["--since", "1h"]).is_empty());
+    let _ = fs::remove_file(&log);
+}
+
+#[test]
+fn dry_runs_are_recorded() {
+    let log = audit_log("dry-run");
+    let server = travis_server();
+    run(&server, &log, &["--dry-run"]);
+    assert!(server.mutations().is_empty());
+
+    let out = audit(&log, &["--build", "11"]);
+    assert!(out.contains("would have cancelled travis build 1 (11)"));
+    let _ = fs::remove_file(&log);
+}
@@ -0,0 +1,227 @@
+//! A tiny HTTP server standing in for the CI services, answering with
+//! scripted responses and recording every request made to it.
+
+// not every test uses every helper
+#![allow(dead_code)]
+
+use std::cmp;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Command, Output};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub method: String,
+    /// Path of the request, without the query string.
+    pub path: String,
+    pub query: String,
+    /// As `Name: value`.
+    pub headers: Vec<String>,
+    pub body: String,
+}
+
+pub struct Route {
+    method: &'static str,
+    path: String,
+    /// Parameters the query string has to contain, as `key=value`.
+    query: Vec<String>,
+    /// Answered in turn, with the last one repeated once they run out.
+    responses: Vec<Response>,
+    served: usize,
+}
+
+struct Response {
+    status: u32,
+    headers: Vec<String>,
+    body: String,
+}
+
+/// Answers `method` requests to `path`, whatever their query string, with a
+/// 200 and `body`.
+pub fn route(method: &'static str, path: &str, body: &str) -> Route {
+    Route {
+        method: method,
+        path: path.to_string(),
+        query: Vec::new(),
+        responses: vec![Response {
+            status: 200,
+            headers: Vec::new(),
+            body: body.to_string(),
+        }],
+        served: 0,
+    }
+}
+
+impl Route {
+    /// Only matches requests whose query string contains each of the `&`
+    /// separated parameters in `query`.
+    pub fn query(mut self, query: &str) -> Route {
+        self.query = query.split('&').map(|p| p.to_string()).collect();
+        self
+    }
+
+    /// Answers with `status` rather than a 200.
+    pub fn status(mut self, status: u32) -> Route {
+        self.responses.last_mut().unwrap().status = status;
+        self
+    }
+
+    /// Adds `header`, given as `Name: value`, to the response.
+    pub fn header(mut self, header: &str) -> Route {
+        let response = self.responses.last_mut().unwrap();
+        response.headers.push(header.to_string());
+        self
+    }
+
+    /// Answers with a 200 and `body` once the responses before it have been
+    /// sent, which `status` and `header` then apply to.
+    pub fn then(mut self, body: &str) -> Route {
+        self.responses.push(Response {
+            status: 200,
+            headers: Vec::new(),
+            body: body.to_string(),
+        });
+        self
+    }
+
+    fn matches(&self, request: &Request) -> bool {
+        let params = request.query.split('&').collect::<Vec<_>>();
+        self.method == request.method
+            && self.path == request.path
+            && self.query.iter().all(|p| params.contains(&&p[..]))
+    }
+
+    fn respond(&mut self) -> &Response {
+        let i = cmp::min(self.served, self.responses.len() - 1);
+        self.served += 1;
+        &self.responses[i]
+    }
+}
+
+pub struct Server {
+    url: String,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl Server {
+    /// Serves `routes`, trying the ones with a query first.
+    pub fn start(mut routes: Vec<Route>) -> Server {
+        routes.sort_by_key(|r| r.query.is_empty());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let requests2 = requests.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                if let Ok(request) = handle(stream, &mut routes) {
+                    requests2.lock().unwrap().push(request);
+                }
+            }
+        });
+        Server {
+            url: url,
+            requests: requests,
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// Everything but the `GET`s, as `METHOD /path`, which is all that can
+    /// have cancelled something.
+    pub fn mutations(&self) -> Vec<String> {
+        self.requests()
+            .iter()
+            .filter(|r| r.method != "GET")
+            .map(|r| format!("{} {}", r.method, r.path))
+            .collect()
+    }
+}
+
+fn handle(stream: TcpStream, routes: &mut [Route]) -> io::Result<Request> {
+    let mut reader = BufReader::new(try!(stream.try_clone()));
+    let mut line = String::new();
+    try!(reader.read_line(&mut line));
+    let mut parts = line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method.to_string(), target.to_string()),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "bad request line",
+            ))
+        }
+    };
+
+    let mut length = 0;
+    let mut headers = Vec::new();
+    loop {
+        let mut header = String::new();
+        try!(reader.read_line(&mut header));
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let mut kv = header.splitn(2, ':');
+        let (key, value) = (kv.next().unwrap_or(""), kv.next().unwrap_or("").trim());
+        if key.eq_ignore_ascii_case("content-length") {
+            length = value.parse().unwrap_or(0);
+        }
+        headers.push(header.to_string());
+    }
+    let mut body = vec![0; length];
+    try!(reader.read_exact(&mut body));
+
+    let mut target = target.splitn(2, '?');
+    let request = Request {
+        method: method,
+        path: target.next().unwrap_or("").to_string(),
+        query: target.next().unwrap_or("").to_string(),
+        headers: headers,
+        body: String::from_utf8_lossy(&body).into_owned(),
+    };
+
+    let response = routes
+        .iter_mut()
+        .find(|r| r.matches(&request))
+        .map(|r| r.respond());
+    let (status, headers, body) = match response {
+        Some(response) => (response.status, &response.headers[..], &response.body[..]),
+        None => (404, &[][..], "{}"),
+    };
+    let mut head = format!(
+        "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n",
+        status,
+        body.len()
+    );
+    for header in headers {
+        head.push_str(header);
+        head.push_str("\r\n");
+    }
+    let mut stream = stream;
+    let _ = write!(stream, "{}\r\n{}", head, body);
+    Ok(request)
+}
+
+/// Runs cancelbot once with `args`.
+pub fn cancelbot(args: &[&str]) -> Output {
+    let output = Command::new(env!("CARGO_BIN_EXE_cancelbot"))
+        .args(args)
+        .output()
+        .unwrap();
+    println!("{}", String::from_utf8_lossy(&output.stdout));
+    println!("{}", String::from_utf8_lossy(&output.stderr));
+    output
+}
@@ -0,0 +1,1113 @@
+//! Runs cancelbot against scripted Travis, AppVeyor and Azure Pipelines
+//! build histories and checks exactly which builds it cancels.
+
+mod mock;
+
+use std::path::Path;
+use std::process::Command;
+
+use mock::{cancelbot, route, Server};
+
+fn travis(server: &Server) -> Vec<String> {
+    cancelbot(&[
+        "--travis-url",
+        server.url(),
+        "-t",
+        "token",
+        "-b",
+        "auto",
+        "rust-lang/rust",
+    ]);
+    server.mutations()
+}
+
+fn travis_builds(builds: &[(u32, &str)]) -> String {
+    travis_page(builds, None)
+}
+
+/// A page of builds, followed by another at `next` if there is one.
+fn travis_page(builds: &[(u32, &str)], next: Option<u32>) -> String {
+    let builds = builds
+        .iter()
+        .map(|&(number, state)| {
+            format!(
+                r#"{{"id": {0}, "number": "{0}", "state": "{1}",
+                    "branch": {{"name": "auto"}}, "commit": {{"sha": "sha{0}"}}}}"#,
+                number, state
+            )
+        })
+        .collect::<Vec<_>>();
+    let pagination = match next {
+        Some(offset) => format!(r#"{{"is_last": false, "next": {{"offset": {}}}}}"#, offset),
+        None => r#"{"is_last": true, "next": null}"#.to_string(),
+    };
+    format!(
+        r#"{{"@pagination": {}, "builds": [{}]}}"#,
+        pagination,
+        builds.join(",")
+    )
+}
+
+fn travis_jobs(jobs: &[(u32, &str, bool)]) -> String {
+    let jobs = jobs
+        .iter()
+        .map(|&(id, state, allow_failure)| {
+            format!(
+                r#"{{"id": {}, "state": "{}", "allow_failure": {}}}"#,
+                id, state, allow_failure
+            )
+        })
+        .collect::<Vec<_>>();
+    format!(r#"{{"jobs": [{}]}}"#, jobs.join(","))
+}
+
+#[test]
+fn travis_cancels_older_running_build() {
+    let server = Server::start(vec![
+        route(
+            "GET",
+            "/repo/rust-lang%2Frust/builds",
+            &travis_builds(&[(2, "started"), (1, "started")]),
+        ),
+        route(
+            "GET",
+            "/build/2/jobs",
+            &travis_jobs(&[(20, "started", false)]),
+        ),
+        route("POST", "/build/1/cancel", "{}"),
+    ]);
+    assert_eq!(travis(&server), vec!["POST /build/1/cancel"]);
+}
+
+#[test]
+fn travis_cancels_restarted_build_superseded_by_a_finished_one() {
+    let server = Server::start(vec![
+        route(
+            "GET",
+            "/repo/rust-lang%2Frust/builds",
+            &travis_builds(&[(3, "passed"), (2, "started")]),
+        ),
+        route("POST", "/build/2/cancel", "{}"),
+    ]);
+    assert_eq!(travis(&server), vec!["POST /build/2/cancel"]);
+    assert!(!server.requests()[0].query.contains("state="));
+}
+
+#[test]
+fn travis_cancels_latest_build_with_failed_job() {
+    let server = Server::start(vec![
+        route(
+            "GET",
+            "/repo/rust-lang%2Frust/builds",
+            &travis_builds(&[(2, "started")]),
+        ),
+        route(
+            "GET",
+            "/build/2/jobs",
+            &travis_jobs(&[(20, "passed", false), (21, "failed", false)]),
+        ),
+        route("POST", "/build/2/cancel", "{}"),
+    ]);
+    assert_eq!(travis(&server), vec!["POST /build/2/cancel"]);
+}
+
+#[test]
+fn travis_ignores_failed_jobs_allowed_to_fail() {
+    let server = Server::start(vec![
+        route(
+            "GET",
+            "/repo/rust-lang%2Frust/builds",
+            &travis_builds(&[(2, "started")]),
+        ),
+        route(
+            "GET",
+            "/build/2/jobs",
+            &travis_jobs(&[(20, "failed", true)]),
+        ),
+    ]);
+    assert!(travis(&server).is_empty());
+}
+
+#[test]
+fn travis_nothing_to_do() {
+    let server = Server::start(vec![
+        route(
+            "GET",
+            "/repo/rust-lang%2Frust/builds",
+            &travis_builds(&[(2, "started")]),
+        ),
+        route(
+            "GET",
+            "/build/2/jobs",
+            &travis_jobs(&[(20, "started", false)]),
+        ),
+    ]);
+    assert!(travis(&server).is_empty());
+    assert!(server.requests()[0].query.contains("branch.name=auto"));
+    let paths = server
+        .requests()
+        .iter()
+        .map(|r| r.path.clone())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        paths,
+        vec!["/repo/rust-lang%2Frust/builds", "/build/2/jobs"]
+    );
+}
+
+#[test]
+fn travis_keeps_checking_other_repos_after_a_failure() {
+    let server = Server::start(vec![
+        route(
+            "GET",
+            "/repo/rust-lang%2Fcargo/builds",
+            &travis_builds(&[(2, "started"), (1, "started")]),
+        ),
+        route("GET", "/build/2/jobs", &travis_jobs(&[])),
+        route("POST", "/build/1/cancel", "{}"),
+    ]);
+    // rust-lang/rust isn't routed, so listing its builds fails with a 404
+    let output = cancelbot(&[
+        "--travis-url",
+        server.url(),
+        "-t",
+        "token",
+        "-b",
+        "auto",
+        "rust-lang/rust",
+        "rust-lang/cargo",
+    ]);
+    assert!(output.status.success());
+    assert_eq!(server.mutations(), vec!["POST /build/1/cancel"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("failed to check rust-lang/rust auto on travis"));
+}
+
+#[test]
+fn travis_invalid_build_number_is_an_error() {
+    let server = Server::start(vec![route(
+        "GET",
+        "/repo/rust-lang%2Frust/builds",
+        r#"{"@pagination": {"is_last": true, "next": null}, "builds": [
+            {"id": 7, "number": "seven", "state": "started",
+             "branch": {"name": "auto"}, "commit": {"sha": "sha7"}}]}"#,
+    )]);
+    let output = cancelbot(&[
+        "--travis-url",
+        server.url(),
+        "-t",
+        "token",
+        "-b",
+        "auto",
+        "rust-lang/rust",
+    ]);
+    assert!(output.status.success());
+    assert!(server.mutations().is_empty());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("build number `seven` isn't a number"));
+}
+
+#[test]
+fn travis_follows_pagination() {
+    let server = Server::start(vec![
+        route(
+            "GET",
+            "/repo/rust-lang%2Frust/builds",
+            &travis_page(&[(3, "started")], Some(1)),
+        ),
+        route(
+            "GET",
+            "/repo/rust-lang%2Frust/builds",
+            &travis_builds(&[(2, "started")]),
+        )
+        .query("offset=1"),
+        route("GET", "/build/3/jobs", &travis_jobs(&[])),
+        route("POST", "/build/2/cancel", "{}"),
+    ]);
+    assert_eq!(travis(&server), vec!["POST /build/2/cancel"]);
+}
+
+#[test]
+fn travis_retries_gets_after_a_bad_gateway() {
+    let server = Server::start(vec![
+        route("GET", "/repo/rust-lang%2Frust/builds", "bad gateway")
+            .status(502)
+            .then(&travis_builds(&[(2, "started"), (1, "started")])),
+        route("GET", "/build/2/jobs", &travis_jobs(&[])),
+        route("POST", "/build/1/cancel", "{}"),
+    ]);
+    assert_eq!(travis(&server), vec!["POST /build/1/cancel"]);
+    let listings = server
+        .requests()
+        .iter()
+        .filter(|r| r.path == "/repo/rust-lang%2Frust/builds")
+        .count();
+    assert_eq!(listings, 2);
+}
+
+#[test]
+fn travis_does_not_retry_a_failed_cancel() {
+    let server = Server::start(vec![
+        route(
+            "GET",
+            "/repo/rust-lang%2Frust/builds",
+            &travis_builds(&[(2, "started"), (1, "started")]),
+        ),
+        route("GET", "/build/2/jobs", &travis_jobs(&[])),
+        // the build may have been cancelled all the same
+        route("POST", "/build/1/cancel", "oops").status(500),
+    ]);
+    assert_eq!(travis(&server), vec!["POST /build/1/cancel"]);
+}
+
+#[test]
+fn jobs_are_not_fetched_when_the_rate_limit_is_low() {
+    let server = Server::start(vec![
+        route(
+            "GET",
+            "/repo/rust-lang%2Frust/builds",
+            &travis_builds(&[(2, "started"), (1, "started")]),
+        )
+        .header("X-RateLimit-Limit: 100")
+        .header("X-RateLimit-Remaining: 3"),
+        route("GET", "/build/2/jobs", &travis_jobs(&[])),
+        route("POST", "/build/1/cancel", "{}"),
+    ]);
+    // superseded builds are still cancelled
+    assert_eq!(travis(&server), vec!["POST /build/1/cancel"]);
+    assert!(server.requests().iter().all(|r| r.path != "/build/2/jobs"));
+}
+
+#[test]
+fn rate_limits_are_kept_per_token() {
+    let server = Server::start(vec![
+        route(
+            "GET",
+            "/repo/rust-lang%2Frust/builds",
+            &travis_builds(&[(2, "started")]),
+        )
+        .header("X-RateLimit-Limit: 100")
+        .header("X-RateLimit-Remaining: 3"),
+        route("GET", "/build/2/jobs", &travis_jobs(&[])),
+        route(
+            "GET",
+            "/repo/rust-lang%2Fcargo/builds",
+            &travis_builds(&[(3, "started")]),
+        )
+        .header("X-RateLimit-Limit: 100")
+        .header("X-RateLimit-Remaining: 90"),
+        route("GET", "/build/3/jobs", &travis_jobs(&[])),
+    ]);
+    let path = std::env::temp_dir().join(format!("cancelbot-limits-{}.toml", std::process::id()));
+    let config = "[[repo]]\n\
+                  name = \"rust-lang/rust\"\n\
+                  travis = { token = \"rust-token\" }\n\
+                  [[repo]]\n\
+                  name = \"rust-lang/cargo\"\n\
+                  travis = { token = \"cargo-token\" }\n";
+    std::fs::write(&path, config).unwrap();
+    cancelbot(&[
+        "-c",
+        path.to_str().unwrap(),
+        "--travis-url",
+        server.url(),
+        "-b",
+        "auto",
+    ]);
+    let _ = std::fs::remove_file(&path);
+
+    let paths = server
+        .requests()
+        .iter()
+        .map(|r| r.path.clone())
+        .collect::<Vec<_>>();
+    assert!(!paths.contains(&"/build/2/jobs".to_string()));
+    assert!(paths.contains(&"/build/3/jobs".to_string()));
+}
+
+#[test]
+fn invalid_intervals_are_rejected() {
+    for interval in &["0", "0m", "99999999999999999999h", "9999999999999999h"] {
+        let output = cancelbot(&["--interval", interval, "-b", "auto", "rust-lang/rust"]);
+        assert!(!output.status.success());
+        assert!(String::from_utf8_lossy(&output.stdout).contains("error: "));
+        assert!(!String::from_utf8_lossy(&output.stderr).contains("panicked"));
+    }
+}
+
+#[test]
+fn tokens_are_redacted_and_bodies_truncated_in_errors() {
+    let body = format!(
+        "not json, but the token sekrit-travis-token and then {}",
+        "x".repeat(1000)
+    );
+    let server = Server::start(vec![route("GET", "/repo/rust-lang%2Frust/builds", &body)]);
+    let output = cancelbot(&[
+        "--travis-url",
+        server.url(),
+        "-t",
+        "sekrit-travis-token",
+        "--max-body-excerpt",
+        "100",
+        "-b",
+        "auto",
+        "rust-lang/rust",
+    ]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("failed to decode the response"));
+    assert!(stdout.contains("the token [REDACTED] and then xxx"));
+    assert!(!stdout.contains("sekrit-travis-token"));
+    assert!(!stdout.contains(&"x".repeat(200)));
+}
+
+#[test]
+fn long_retry_waits_keep_the_original_error() {
+    let server = Server::start(vec![route(
+        "GET",
+        "/repo/rust-lang%2Frust/builds",
+        "slow down",
+    )
+    .status(429)
+    .header("Retry-After: 600")]);
+    let output = cancelbot(&[
+        "--travis-url",
+        server.url(),
+        "-t",
+        "token",
+        "-b",
+        "auto",
+        "rust-lang/rust",
+    ]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("not waiting 600s to retry the request to travis"));
+    assert!(stdout.contains("with 429: slow down"));
+    assert_eq!(server.requests().len(), 1);
+}
+
+#[test]
+fn invalid_buildkite_pipelines_are_named() {
+    let output = cancelbot(&[
+        "--buildkite-token",
+        "token",
+        "--buildkite-pipeline",
+        "rust-lang/rust=rust-ci",
+        "-b",
+        "auto",
+        "rust-lang/rust",
+    ]);
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("--buildkite-pipeline `rust-lang/rust=rust-ci` isn't"));
+}
+
+#[test]
+fn github_heads_need_a_github_token() {
+    let output = cancelbot(&[
+        "-t",
+        "token",
+        "-b",
+        "auto",
+        "--github-heads",
+        "rust-lang/rust",
+    ]);
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("branch heads of rust-lang/rust on GitHub needs --github-token"));
+}
+
+#[test]
+fn invalid_repository_name_is_rejected() {
+    let output = cancelbot(&["-t", "token", "-b", "auto", "rust"]);
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("error: repository `rust` isn't `user/name`"));
+    assert!(!String::from_utf8_lossy(&output.stderr).contains("panicked"));
+}
+
+#[test]
+fn unknown_providers_in_the_config_are_rejected() {
+    let server = Server::start(vec![route(
+        "GET",
+        "/repo/rust-lang%2Frust/builds",
+        &travis_builds(&[]),
+    )]);
+    let path =
+        std::env::temp_dir().join(format!("cancelbot-providers-{}.toml", std::process::id()));
+    let run = |providers: &str| {
+        let config = format!(
+            "[[repo]]\nname = \"rust-lang/rust\"\nproviders = [{}]\n",
+            providers
+        );
+        std::fs::write(&path, config).unwrap();
+        cancelbot(&[
+            "-c",
+            path.to_str().unwrap(),
+            "--travis-url",
+            server.url(),
+            "-t",
+            "token",
+            "-b",
+            "auto",
+        ])
+    };
+
+    let output = run(r#""travis", "azure""#);
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("unknown provider `azure` for rust-lang/rust"));
+    assert!(stdout.contains("expected one of travis, appveyor, azure_pipelines"));
+
+    let output = run(
+        r#""travis", "appveyor", "azure_pipelines", "github", "gitlab",
+           "circleci", "buildkite""#,
+    );
+    assert!(output.status.success());
+    assert_eq!(server.requests().len(), 1);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn repositories_with_their_own_url_use_their_own_token() {
+    let global = Server::start(vec![]);
+    let own = Server::start(vec![
+        route(
+            "GET",
+            "/repo/rust-lang%2Frust/builds",
+            &travis_builds(&[(2, "started"), (1, "started")]),
+        ),
+        route("GET", "/build/2/jobs", &travis_jobs(&[])),
+        route("POST", "/build/1/cancel", "{}"),
+    ]);
+    let path = std::env::temp_dir().join(format!("cancelbot-token-{}.toml", std::process::id()));
+    let run = |travis: &str| {
+        let config = format!(
+            "[[repo]]\nname = \"rust-lang/rust\"\ntravis = {{ {} }}\n",
+            travis
+        );
+        std::fs::write(&path, config).unwrap();
+        cancelbot(&[
+            "-c",
+            path.to_str().unwrap(),
+            "--travis-url",
+            global.url(),
+            "-t",
+            "global-token",
+            "-b",
+            "auto",
+        ])
+    };
+
+    let output = run(&format!(r#"url = "{}""#, own.url()));
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("rust-lang/rust sets its own travis url, so it needs a token"));
+    assert!(own.requests().is_empty());
+
+    let output = run(&format!(r#"url = "{}", token = "own-token""#, own.url()));
+    assert!(output.status.success());
+    assert_eq!(own.mutations(), vec!["POST /build/1/cancel"]);
+    for request in own.requests() {
+        assert!(request
+            .headers
+            .contains(&"Authorization: token own-token".to_string()));
+    }
+    assert!(global.requests().is_empty());
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn repositories_with_their_own_token_need_no_global_token() {
+    let server = Server::start(vec![
+        route(
+            "GET",
+            "/repo/rust-lang%2Frust/builds",
+            &travis_builds(&[(2, "started"), (1, "started")]),
+        ),
+        route("GET", "/build/2/jobs", &travis_jobs(&[])),
+        route("POST", "/build/1/cancel", "{}"),
+    ]);
+    let path = std::env::temp_dir().join(format!("cancelbot-own-{}.toml", std::process::id()));
+    let config = "[[repo]]\n\
+                  name = \"rust-lang/rust\"\n\
+                  travis = { token = \"own-token\" }\n\
+                  [[repo]]\n\
+                  name = \"rust-lang/cargo\"\n";
+    std::fs::write(&path, config).unwrap();
+    let output = cancelbot(&[
+        "-c",
+        path.to_str().unwrap(),
+        "--travis-url",
+        server.url(),
+        "-b",
+        "auto",
+    ]);
+    let _ = std::fs::remove_file(&path);
+
+    assert!(output.status.success());
+    assert_eq!(server.mutations(), vec!["POST /build/1/cancel"]);
+    // without a token of its own, cargo isn't checked on Travis at all
+    for request in server.requests() {
+        assert!(!request.path.contains("cargo"));
+        assert!(request
+            .headers
+            .contains(&"Authorization: token own-token".to_string()));
+    }
+}
+
+/// Runs git in `dir`, returning its trimmed output.
+fn git(dir: &Path, args: &[&str]) -> String {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .env("GIT_AUTHOR_NAME", "cancelbot")
+        .env("GIT_AUTHOR_EMAIL", "cancelbot@example.com")
+        .env("GIT_COMMITTER_NAME", "cancelbot")
+        .env("GIT_COMMITTER_EMAIL", "cancelbot@example.com")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "git {:?} failed", args);
+    String::from_utf8_lossy(&output.stdout).trim().to_string()
+}
+
+#[test]
+fn checkouts_are_fetched_before_looking_up_heads() {
+    let dir = std::env::temp_dir().join(format!("cancelbot-checkout-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let (origin, checkout) = (dir.join("origin"), dir.join("checkout"));
+    std::fs::create_dir_all(&origin).unwrap();
+    git(&origin, &["init", "--quiet", "-b", "auto"]);
+    git(
+        &origin,
+        &["commit", "--quiet", "--allow-empty", "-m", "old"],
+    );
+    let old = git(&origin, &["rev-parse", "HEAD"]);
+    git(
+        &dir,
+        &["clone", "--quiet", origin.to_str().unwrap(), "checkout"],
+    );
+    // the checkout doesn't know about the newest commit yet
+    git(
+        &origin,
+        &["commit", "--quiet", "--allow-empty", "-m", "new"],
+    );
+    let new = git(&origin, &["rev-parse", "HEAD"]);
+
+    let builds = format!(
+        r#"{{"@pagination": {{"is_last": true, "next": null}}, "builds": [
+            {{"id": 2, "number": "2", "state": "started",
+             "branch": {{"name": "auto"}}, "commit": {{"sha": "{}"}}}},
+            {{"id": 1, "number": "1", "state": "started",
+             "branch": {{"name": "auto"}}, "commit": {{"sha": "{}"}}}}]}}"#,
+        new, old
+    );
+    let server = Server::start(vec![
+        route("GET", "/repo/rust-lang%2Frust/builds", &builds),
+        route("GET", "/build/2/jobs", &travis_jobs(&[])),
+        route("POST", "/build/1/cancel", "{}"),
+    ]);
+    let config = dir.join("cancelbot.toml");
+    std::fs::write(
+        &config,
+        format!(
+            "[[repo]]\nname = \"rust-lang/rust\"\ncheckout = \"{}\"\n",
+            checkout.display()
+        ),
+    )
+    .unwrap();
+    cancelbot(&[
+        "-c",
+        config.to_str().unwrap(),
+        "--travis-url",
+        server.url(),
+        "-t",
+        "token",
+        "-b",
+        "auto",
+    ]);
+    let _ = std::fs::remove_dir_all(&dir);
+    assert_eq!(server.mutations(), vec!["POST /build/1/cancel"]);
+}
+
+fn appveyor(server: &Server) -> Vec<String> {
+    cancelbot(&[
+        "--appveyor-url",
+        server.url(),
+        "-a",
+        "token",
+        "--appveyor-account",
+        "acct",
+        "-b",
+        "auto",
+        "rust-lang/rust",
+    ]);
+    server.mutations()
+}
+
+fn appveyor_build(number: u32, status: &str, jobs: &str) -> String {
+    format!(
+        r#"{{"buildId": {0}, "jobs": [{2}], "buildNumber": {0}, "version": "1.0.{0}",
+            "message": "merge", "branch": "auto", "commitId": "sha{0}",
+            "status": "{1}", "started": null, "finished": null,
+            "created": "2019-01-01T00:00:00Z", "updated": null}}"#,
+        number, status, jobs
+    )
+}
+
+fn appveyor_history(builds: &[(u32, &str)]) -> String {
+    let builds = builds
+        .iter()
+        .map(|&(number, status)| appveyor_build(number, status, ""))
+        .collect::<Vec<_>>();
+    format!(
+        r#"{{"project": {{"projectId": 1, "accountId": 1, "accountName": "acct",
+            "name": "rust", "slug": "rust", "repositoryName": "rust-lang/rust",
+            "repositoryType": "gitHub"}}, "builds": [{}]}}"#,
+        builds.join(",")
+    )
+}
+
+fn appveyor_last_build(number: u32, jobs: &[(&str, &str, bool)]) -> String {
+    let jobs = jobs
+        .iter()
+        .map(|&(id, status, allow_failure)| {
+            format!(
+                r#"{{"jobId": "{}", "status": "{}", "allowFailure": {}}}"#,
+                id, status, allow_failure
+            )
+        })
+        .collect::<Vec<_>>();
+    format!(
+        r#"{{"build": {}}}"#,
+        appveyor_build(number, "running", &jobs.join(","))
+    )
+}
+
+#[test]
+fn appveyor_cancels_older_running_build() {
+    let server = Server::start(vec![
+        route(
+            "GET",
+            "/api/projects/acct/rust/history",
+            &appveyor_history(&[(2, "queued"), (1, "running")]),
+        ),
+        route(
+            "GET",
+            "/api/projects/acct/rust/build/1.0.2",
+            &appveyor_last_build(2, &[]),
+        ),
+        route("DELETE", "/api/builds/acct/rust/1.0.1", ""),
+    ]);
+    assert_eq!(
+        appveyor(&server),
+        vec!["DELETE /api/builds/acct/rust/1.0.1"]
+    );
+}
+
+#[test]
+fn appveyor_cancels_latest_build_with_failed_job() {
+    let server = Server::start(vec![
+        route(
+            "GET",
+            "/api/projects/acct/rust/history",
+            &appveyor_history(&[(2, "running")]),
+        ),
+        route(
+            "GET",
+            "/api/projects/acct/rust/build/1.0.2",
+            &appveyor_last_build(2, &[("a", "success", false), ("b", "failed", false)]),
+        ),
+        route("DELETE", "/api/builds/acct/rust/1.0.2", ""),
+    ]);
+    assert_eq!(
+        appveyor(&server),
+        vec!["DELETE /api/builds/acct/rust/1.0.2"]
+    );
+}
+
+#[test]
+fn appveyor_nothing_to_do() {
+    let server = Server::start(vec![
+        route(
+            "GET",
+            "/api/projects/acct/rust/history",
+            &appveyor_history(&[(2, "running"), (1, "success")]),
+        ),
+        route(
+            "GET",
+            "/api/projects/acct/rust/build/1.0.2",
+            &appveyor_last_build(2, &[("a", "running", false), ("b", "failed", true)]),
+        ),
+    ]);
+    assert!(appveyor(&server).is_empty());
+}
+
+fn azure(server: &Server) -> Vec<String> {
+    cancelbot(&[
+        "--azure-url",
+        server.url(),
+        "--azure-pipelines-token",
+        "token",
+        "-b",
+        "auto",
+        "rust-lang/rust",
+    ]);
+    server.mutations()
+}
+
+fn azure_builds(builds: &[(u32, &str)]) -> String {
+    let builds = builds
+        .iter()
+        .map(|&(id, status)| {
+            format!(
+                r#"{{"id": {0}, "status": "{1}", "result": null,
+                    "sourceBranch": "refs/heads/auto", "sourceVersion": "sha{0}",
+                    "definition": {{"id": 1, "name": "ci"}}}}"#,
+                id, status
+            )
+        })
+        .collect::<Vec<_>>();
+    format!(r#"{{"value": [{}]}}"#, builds.join(","))
+}
+
+fn azure_timeline(records: &[(&str, &str, Option<&str>)]) -> String {
+    let records = records
+        .iter()
+        .map(|&(name, state, result)| {
+            let result = match result {
+                Some(result) => format!("\"{}\"", result),
+                None => "null".to_string(),
+            };
+            format!(
+                r#"{{"id": "{0}", "name": "{0}", "type": "Job", "state": "{1}", "result": {2}}}"#,
+                name, state, result
+            )
+        })
+        .collect::<Vec<_>>();
+    format!(r#"{{"records": [{}]}}"#, records.join(","))
+}
+
+#[test]
+fn azure_cancels_older_running_build() {
+    let server = Server::start(vec![
+        route(
+            "GET",
+            "/rust-lang/rust/_apis/build/builds",
+            &azure_builds(&[(2, "inProgress"), (1, "inProgress")]),
+        ),
+        route(
+            "GET",
+            "/rust-lang/rust/_apis/build/builds/2/timeline",
+            &azure_timeline(&[]),
+        ),
+        route("PATCH", "/rust-lang/rust/_apis/build/builds/1", "{}"),
+    ]);
+    assert_eq!(
+        azure(&server),
+        vec!["PATCH /rust-lang/rust/_apis/build/builds/1"]
+    );
+    let cancel = server
+        .requests()
+        .into_iter()
+        .find(|r| r.method == "PATCH")
+        .unwrap();
+    assert_eq!(cancel.body, r#"{"status":"Cancelling"}"#);
+}
+
+#[test]
+fn azure_cancels_latest_build_with_failed_job() {
+    let server = Server::start(vec![
+        route(
+            "GET",
+            "/rust-lang/rust/_apis/build/builds",
+            &azure_builds(&[(2, "inProgress")]),
+        ),
+        route(
+            "GET",
+            "/rust-lang/rust/_apis/build/builds/2/timeline",
+            &azure_timeline(&[
+                ("linux", "completed", Some("failed")),
+                ("mac", "inProgress", None),
+            ]),
+        ),
+        route("PATCH", "/rust-lang/rust/_apis/build/builds/2", "{}"),
+    ]);
+    assert_eq!(
+        azure(&server),
+        vec!["PATCH /rust-lang/rust/_apis/build/builds/2"]
+    );
+}
+
+#[test]
+fn azure_nothing_to_do() {
+    let server = Server::start(vec![
+        route(
+            "GET",
+            "/rust-lang/rust/_apis/build/builds",
+            &azure_builds(&[(2, "inProgress")]),
+        ),
+        route(
+            "GET",
+            "/rust-lang/rust/_apis/build/builds/2/timeline",
+            &azure_timeline(&[
+                ("linux", "completed", Some("succeededWithIssues")),
+                ("mac", "inProgress", None),
+            ]),
+        ),
+    ]);
+    assert!(azure(&server).is_empty());
+}
+
+#[test]
+fn azure_ignores_failures_of_jobs_continuing_on_error() {
+    let server = Server::start(vec![
+        route(
+            "GET",
+            "/rust-lang/rust/_apis/build/builds",
+            &azure_builds(&[(2, "inProgress")]),
+        ),
+        route(
+            "GET",
+            "/rust-lang/rust/_apis/build/builds/2/timeline",
+            // `lint` failed, but has `continueOnError` set, so its stage
+            // only finished with issues
+            r#"{"records": [
+                {"id": "s1", "name": "check", "type": "Stage",
+                 "state": "completed", "result": "succeededWithIssues"},
+                {"id": "p1", "parentId": "s1", "name": "lint", "type": "Phase",
+                 "state": "completed", "result": "succeededWithIssues"},
+                {"id": "j1", "parentId": "p1", "name": "lint", "type": "Job",
+                 "state": "completed", "result": "failed"},
+                {"id": "s2", "name": "test", "type": "Stage",
+                 "state": "inProgress", "result": null},
+                {"id": "p2", "parentId": "s2", "name": "linux", "type": "Phase",
+                 "state": "inProgress", "result": null},
+                {"id": "j2", "parentId": "p2", "name": "linux", "type": "Job",
+                 "state": "inProgress", "result": null}]}"#,
+        ),
+        route("PATCH", "/rust-lang/rust/_apis/build/builds/2", "{}"),
+    ]);
+    assert!(azure(&server).is_empty());
+}
+
+#[test]
+fn azure_follows_continuation_tokens() {
+    let server = Server::start(vec![
+        route(
+            "GET",
+            "/rust-lang/rust/_apis/build/builds",
+            &azure_builds(&[(3, "inProgress")]),
+        )
+        .header("x-ms-continuationtoken: page2"),
+        route(
+            "GET",
+            "/rust-lang/rust/_apis/build/builds",
+            &azure_builds(&[(2, "inProgress")]),
+        )
+        .query("continuationToken=page2"),
+        route(
+            "GET",
+            "/rust-lang/rust/_apis/build/builds/3/timeline",
+            &azure_timeline(&[]),
+        ),
+        route("PATCH", "/rust-lang/rust/_apis/build/builds/2", "{}"),
+    ]);
+    assert_eq!(
+        azure(&server),
+        vec!["PATCH /rust-lang/rust/_apis/build/builds/2"]
+    );
+}
+
+fn circleci_pipelines(revision: &str) -> String {
+    format!(
+        r#"{{"items": [
+            {{"id": "p2", "number": 2, "state": "created",
+             "vcs": {{"revision": "{}", "branch": "auto"}}}},
+            {{"id": "p1", "number": 1, "state": "created",
+             "vcs": {{"revision": "old", "branch": "auto"}}}}],
+           "next_page_token": null}}"#,
+        revision
+    )
+}
+
+fn circleci_workflow(id: &str, number: u32, status: &str) -> String {
+    format!(
+        r#"{{"items": [{{"id": "{}", "name": "build", "pipeline_number": {},
+                        "status": "{}"}}], "next_page_token": null}}"#,
+        id, number, status
+    )
+}
+
+/// CircleCI, with pipeline 1 superseded by pipeline 2 of `project`.
+fn circleci_server(project: &str) -> Server {
+    Server::start(vec![
+        route(
+            "GET",
+            &format!("/project/{}/pipeline", project),
+            &circleci_pipelines("new"),
+        ),
+        route(
+            "GET",
+            "/pipeline/p2/workflow",
+            &circleci_workflow("w2", 2, "running"),
+        ),
+        route(
+            "GET",
+            "/pipeline/p1/workflow",
+            &circleci_workflow("w1", 1, "running"),
+        ),
+        route(
+            "GET",
+            "/workflow/w2/job",
+            r#"{"items": [], "next_page_token": null}"#,
+        ),
+        route("POST", "/workflow/w1/cancel", "{}"),
+    ])
+}
+
+#[test]
+fn circleci_cancels_workflows_of_older_pipelines() {
+    let server = circleci_server("gh/rust-lang/rust");
+    cancelbot(&[
+        "--circleci-url",
+        server.url(),
+        "--circleci-token",
+        "token",
+        "-b",
+        "auto",
+        "rust-lang/rust",
+    ]);
+    assert_eq!(server.mutations(), vec!["POST /workflow/w1/cancel"]);
+}
+
+#[test]
+fn circleci_projects_can_be_elsewhere_than_github() {
+    let server = circleci_server("bb/acme/widgets");
+    let path = std::env::temp_dir().join(format!("cancelbot-circleci-{}.toml", std::process::id()));
+    let config = "[[repo]]\n\
+                  name = \"acme/widgets\"\n\
+                  circleci = { project = \"bb/acme/widgets\" }\n";
+    std::fs::write(&path, config).unwrap();
+    cancelbot(&[
+        "-c",
+        path.to_str().unwrap(),
+        "--circleci-url",
+        server.url(),
+        "--circleci-token",
+        "token",
+        "-b",
+        "auto",
+    ]);
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(server.mutations(), vec!["POST /workflow/w1/cancel"]);
+}
+
+fn buildkite(server: &Server) -> Vec<String> {
+    cancelbot(&[
+        "--buildkite-url",
+        server.url(),
+        "--buildkite-token",
+        "token",
+        "--buildkite-pipeline",
+        "rust-lang/rust=rust-lang/rust-ci",
+        "-b",
+        "auto",
+        "rust-lang/rust",
+    ]);
+    server.mutations()
+}
+
+fn buildkite_build(number: u32, state: &str, jobs: &[(&str, &str, bool)]) -> String {
+    let jobs = jobs
+        .iter()
+        .map(|&(name, state, soft_failed)| {
+            format!(
+                r#"{{"id": "{0}", "name": "{0}", "state": "{1}", "soft_failed": {2}}}"#,
+                name, state, soft_failed
+            )
+        })
+        .collect::<Vec<_>>();
+    format!(
+        r#"{{"id": "uuid{0}", "number": {0}, "branch": "auto", "commit": "sha{0}",
+            "state": "{1}", "jobs": [{2}]}}"#,
+        number,
+        state,
+        jobs.join(",")
+    )
+}
+
+#[test]
+fn buildkite_cancels_failing_build() {
+    let builds = format!(
+        "[{}]",
+        buildkite_build(
+            2,
+            "failing",
+            &[("linux", "failed", false), ("mac", "running", false)]
+        )
+    );
+    let server = Server::start(vec![
+        route(
+            "GET",
+            "/organizations/rust-lang/pipelines/rust-ci/builds",
+            &builds,
+        ),
+        route(
+            "PUT",
+            "/organizations/rust-lang/pipelines/rust-ci/builds/2/cancel",
+            "{}",
+        ),
+    ]);
+    assert_eq!(
+        buildkite(&server),
+        vec!["PUT /organizations/rust-lang/pipelines/rust-ci/builds/2/cancel"]
+    );
+    let requests = server.requests();
+    assert!(requests[0].query.contains("state[]=failing"));
+    // the jobs came along with the list of builds
+    assert_eq!(requests.len(), 2);
+}
+
+#[test]
+fn buildkite_rate_limits_reset_relative_to_now() {
+    let builds = format!(
+        "[{}]",
+        buildkite_build(2, "failing", &[("linux", "failed", false)])
+    );
+    let server = Server::start(vec![
+        route(
+            "GET",
+            "/organizations/rust-lang/pipelines/rust-ci/builds",
+            &builds,
+        )
+        .header("RateLimit-Limit: 200")
+        .header("RateLimit-Remaining: 5")
+        .header("RateLimit-Reset: 60"),
+        route(
+            "PUT",
+            "/organizations/rust-lang/pipelines/rust-ci/builds/2/cancel",
+            "{}",
+        ),
+    ]);
+    // the limit resets in a minute rather than at the start of 1970, so it's
+    // still low and the jobs aren't looked at
+    assert!(buildkite(&server).is_empty());
+}
+
+#[test]
+fn buildkite_ignores_soft_failures() {
+    let builds = format!(
+        "[{}]",
+        buildkite_build(
+            2,
+            "running",
+            &[("linux", "failed", true), ("mac", "running", false)]
+        )
+    );
+    let server = Server::start(vec![route(
+        "GET",
+        "/organizations/rust-lang/pipelines/rust-ci/builds",
+        &builds,
+    )]);
+    assert!(buildkite(&server).is_empty());
+}
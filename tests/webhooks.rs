@@ -0,0 +1,300 @@
+//! Starts `cancelbot serve` and posts signed and unsigned notifications to
+//! it, checking which of them kick off a check.
+
+mod mock;
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+use mock::{route, Server};
+
+/// Percent-encoded as `s3cr%26t%2Bhook` in query strings.
+const SECRET: &str = "s3cr&t+hook";
+
+const GITHUB_PUSH: &str =
+    r#"{"ref":"refs/heads/auto","repository":{"full_name":"rust-lang/rust"}}"#;
+
+/// HMAC-SHA256 of `GITHUB_PUSH` with `SECRET`.
+const GITHUB_SIGNATURE: &str =
+    "sha256=963a5f99692ce32b85416c71b3ca061b2f4b92897d3236a1163e8813c44a84ab";
+
+const TRAVIS_PAYLOAD: &str = r#"{"branch":"auto","pull_request":false,"repository":{"owner_name":"rust-lang","name":"rust"}}"#;
+
+/// RSA-SHA1 signature of `TRAVIS_PAYLOAD` with the key matching
+/// `TRAVIS_KEY`.
+const TRAVIS_SIGNATURE: &str = "RFIberWjDW1WPCHZUdmQrlsS6BF7EcFCZxCq5a9ptiPEeZBAxIEqd1KbGMDeqD1L\
+                                H2WBD1Zz2EaYmQKcyc5nVYZTffPGXGDQsEow9u+seRLTNKAOSyqFt0LXGF5d98q0\
+                                /LQ9nFafI7bgd4KCBnLXS9CyDJVBdgEhahfveJaSmSM=";
+
+const TRAVIS_KEY: &str = "-----BEGIN PUBLIC KEY-----
+MIGfMA0GCSqGSIb3DQEBAQUAA4GNADCBiQKBgQC5icRJmn2PntJeKUgnsXOug+yh
+J5H/xplVW6LpaIJ2ISsx/q5r246TT0MuM3zkR9XuerxS7nZQkQtluJxm6Le8JmRo
+nOcWOufPFZbQh4YcMUYmh270UdfFIBLOvxSN8PdKGlpeqSNlH4AEjLdOFw1MHm69
+VB9XHo4V83b/FWcx+QIDAQAB
+-----END PUBLIC KEY-----";
+
+/// cancelbot running in the background until dropped.
+struct Cancelbot {
+    child: Child,
+    addr: String,
+}
+
+impl Cancelbot {
+    fn start(args: &[&str]) -> Cancelbot {
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let child = Command::new(env!("CARGO_BIN_EXE_cancelbot"))
+            .arg("--listen")
+            .arg(&addr)
+            .args(args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        for _ in 0..100 {
+            if TcpStream::connect(&addr[..]).is_ok() {
+                return Cancelbot {
+                    child: child,
+                    addr: addr,
+                };
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        let mut child = child;
+        let _ = child.kill();
+        let _ = child.wait();
+        panic!("cancelbot never started listening on {}", addr);
+    }
+
+    /// Sends a request, returning the status and body of the response.
+    fn request(&self, method: &str, target: &str, headers: &[&str], body: &str) -> (u32, String) {
+        let mut stream = TcpStream::connect(&self.addr[..]).unwrap();
+        let mut request = format!(
+            "{} {} HTTP/1.0\r\nHost: {}\r\nContent-Length: {}\r\n",
+            method,
+            target,
+            self.addr,
+            body.len()
+        );
+        for header in headers {
+            request.push_str(header);
+            request.push_str("\r\n");
+        }
+        request.push_str("\r\n");
+        request.push_str(body);
+        stream.write_all(request.as_bytes()).unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let status = response
+            .split_whitespace()
+            .nth(1)
+            .and_then(|s| s.parse().ok())
+            .unwrap();
+        let body = response
+            .find("\r\n\r\n")
+            .map(|i| &response[i + 4..])
+            .unwrap_or("");
+        (status, body.to_string())
+    }
+
+    fn post(&self, target: &str, headers: &[&str], body: &str) -> (u32, String) {
+        self.request("POST", target, headers, body)
+    }
+}
+
+impl Drop for Cancelbot {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Travis, with a build superseded by another and the key notifications are
+/// signed with.
+fn travis_server() -> Server {
+    Server::start(vec![
+        route(
+            "GET",
+            "/config",
+            &format!(
+                r#"{{"config": {{"notifications": {{"webhook": {{"public_key": "{}"}}}}}}}}"#,
+                TRAVIS_KEY.replace('\n', "\\n")
+            ),
+        ),
+        route(
+            "GET",
+            "/repo/rust-lang%2Frust/builds",
+            r#"{"@pagination": {"is_last": true, "next": null}, "builds": [
+                {"id": 2, "number": "2", "state": "started",
+                 "branch": {"name": "auto"}, "commit": {"sha": "sha2"}},
+                {"id": 1, "number": "1", "state": "started",
+                 "branch": {"name": "auto"}, "commit": {"sha": "sha1"}}]}"#,
+        ),
+        route("GET", "/build/2/jobs", r#"{"jobs": []}"#),
+        route("POST", "/build/1/cancel", "{}"),
+    ])
+}
+
+fn serve(server: &Server) -> Cancelbot {
+    Cancelbot::start(&[
+        "--travis-url",
+        server.url(),
+        "-t",
+        "token",
+        "--webhook-secret",
+        SECRET,
+        "-b",
+        "auto",
+        "serve",
+        "rust-lang/rust",
+    ])
+}
+
+/// Encodes `payload` the way Travis posts it.
+fn form(payload: &str) -> String {
+    let encoded = payload
+        .bytes()
+        .map(|b| match b {
+            b' ' => "+".to_string(),
+            b if b.is_ascii_alphanumeric() => (b as char).to_string(),
+            b => format!("%{:02X}", b),
+        })
+        .collect::<String>();
+    format!("payload={}", encoded)
+}
+
+/// Waits a while for a check started in the background to cancel something.
+fn mutations(server: &Server) -> Vec<String> {
+    for _ in 0..100 {
+        if !server.mutations().is_empty() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    server.mutations()
+}
+
+#[test]
+fn github_pushes_need_a_valid_signature() {
+    let server = travis_server();
+    let cancelbot = serve(&server);
+    let event = "X-GitHub-Event: push";
+
+    let (status, _) = cancelbot.post("/github", &[event], GITHUB_PUSH);
+    assert_eq!(status, 401);
+    let forged = "X-Hub-Signature-256: sha256=963a5f99692ce32b85416c71b3ca061b2f4b92897d3236a1163e8813c44a84ac";
+    let (status, _) = cancelbot.post("/github", &[event, forged], GITHUB_PUSH);
+    assert_eq!(status, 401);
+    assert!(server.mutations().is_empty());
+
+    let signature = format!("X-Hub-Signature-256: {}", GITHUB_SIGNATURE);
+    let (status, _) = cancelbot.post(
+        "/github",
+        &["X-GitHub-Event: ping", &signature],
+        GITHUB_PUSH,
+    );
+    assert_eq!(status, 200);
+    let (status, body) = cancelbot.post("/github", &[event, &signature], GITHUB_PUSH);
+    assert_eq!((status, &body[..]), (202, "checking rust-lang/rust auto"));
+    assert_eq!(mutations(&server), vec!["POST /build/1/cancel"]);
+}
+
+#[test]
+fn travis_notifications_need_the_secret_and_a_signature() {
+    let server = travis_server();
+    let cancelbot = serve(&server);
+    let body = form(TRAVIS_PAYLOAD);
+    let signature = format!("Signature: {}", TRAVIS_SIGNATURE);
+
+    let (status, _) = cancelbot.post("/travis", &[&signature], &body);
+    assert_eq!(status, 401);
+    let (status, _) = cancelbot.post("/travis?secret=s3cr%26t%2Bhook", &[], &body);
+    assert_eq!(status, 401);
+    let tampered = body.replace("auto", "master");
+    let (status, _) = cancelbot.post("/travis?secret=s3cr%26t%2Bhook", &[&signature], &tampered);
+    assert_eq!(status, 401);
+    assert!(server.mutations().is_empty());
+
+    let (status, body) = cancelbot.post("/travis?secret=s3cr%26t%2Bhook", &[&signature], &body);
+    assert_eq!((status, &body[..]), (202, "checking rust-lang/rust auto"));
+    assert_eq!(mutations(&server), vec!["POST /build/1/cancel"]);
+    // the key is only fetched once
+    let configs = server
+        .requests()
+        .iter()
+        .filter(|r| r.path == "/config")
+        .count();
+    assert_eq!(configs, 1);
+}
+
+#[test]
+fn appveyor_and_azure_notifications_need_the_secret() {
+    let server = travis_server();
+    let cancelbot = serve(&server);
+    let appveyor = r#"{"eventData": {"repositoryName": "rust-lang/rust", "branch": "auto"}}"#;
+    let azure = r#"{"resource": {"repository": {"id": "rust-lang/rust"},
+                                 "sourceBranch": "refs/heads/auto"}}"#;
+
+    for &(path, body) in &[("/appveyor", appveyor), ("/azure", azure)] {
+        let (status, _) = cancelbot.post(path, &[], body);
+        assert_eq!(status, 401);
+        // the secret has to be percent-decoded to match
+        let (status, _) = cancelbot.post(&format!("{}?secret=s3cr&t+hook", path), &[], body);
+        assert_eq!(status, 401);
+        let (status, _) = cancelbot.post(&format!("{}?secret=s3cr%26t%2Bhook", path), &[], body);
+        assert_eq!(status, 202);
+    }
+}
+
+#[test]
+fn unknown_webhooks_and_repositories_are_not_found() {
+    let server = travis_server();
+    let cancelbot = serve(&server);
+    let azure = r#"{"resource": {"repository": {"id": "rust-lang/cargo"},
+                                 "sourceBranch": "refs/heads/auto"}}"#;
+
+    let (status, _) = cancelbot.post("/gitlab?secret=s3cr%26t%2Bhook", &[], "{}");
+    assert_eq!(status, 404);
+    let (status, body) = cancelbot.post("/azure?secret=s3cr%26t%2Bhook", &[], azure);
+    assert_eq!((status, &body[..]), (404, "not watching rust-lang/cargo"));
+    let (status, _) = cancelbot.request("GET", "/github", &[], "");
+    assert_eq!(status, 405);
+    assert!(server.mutations().is_empty());
+}
+
+#[test]
+fn webhooks_are_disabled_without_a_secret() {
+    let server = Server::start(vec![route(
+        "GET",
+        "/repo/rust-lang%2Frust/builds",
+        r#"{"@pagination": {"is_last": true, "next": null}, "builds": []}"#,
+    )]);
+    let cancelbot = Cancelbot::start(&[
+        "--travis-url",
+        server.url(),
+        "-t",
+        "token",
+        "-b",
+        "auto",
+        "--interval",
+        "1h",
+        "rust-lang/rust",
+    ]);
+
+    let signature = format!("X-Hub-Signature-256: {}", GITHUB_SIGNATURE);
+    let (status, body) = cancelbot.post(
+        "/github",
+        &["X-GitHub-Event: push", &signature],
+        GITHUB_PUSH,
+    );
+    assert_eq!((status, &body[..]), (404, "webhooks aren't enabled"));
+    let (status, _) = cancelbot.request("GET", "/metrics", &[], "");
+    assert_eq!(status, 200);
+}